@@ -1,26 +1,88 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use clap::Parser;
+use serde::Deserialize;
 
 use rules_minidock_tools::container_specs::{ConfigDelta, Manifest};
+use rules_minidock_tools::hash::sha256_value::Sha256Value;
+use rules_minidock_tools::integrity;
+use rules_minidock_tools::lock_file::{self, LockFile};
+use rules_minidock_tools::merge_config::RemoteMetadata;
+use rules_minidock_tools::oci_layout::{Descriptor, OciLayout, REF_NAME_ANNOTATION};
+use rules_minidock_tools::registry::ops::size_to_string;
+use rules_minidock_tools::registry::{
+    ContentAndContentType, DockerAuthenticationHelper, Registry, RegistryConnections,
+};
+use rules_minidock_tools::rootfs;
+use rules_minidock_tools::PathPair;
+
+// Layers of a single image downloaded at once.
+const CONCURRENT_LAYER_DOWNLOADS: usize = 8;
 
 // cargo run --bin puller-app -- --registry l.gcr.io --repository google/bazel --digest sha256:08434856d8196632b936dd082b8e03bae0b41346299aedf60a0d481ab427a69f --architecture=x86_64
 
 #[derive(Parser, Debug)]
 #[clap(name = "puller app")]
 struct Opt {
+    #[clap(long, required_unless_present_any = ["resolve_lock_file", "check_lock_file", "batch"])]
+    registry: Option<String>,
+
+    #[clap(long, required_unless_present_any = ["resolve_lock_file", "check_lock_file", "batch"])]
+    repository: Option<String>,
+
+    #[clap(long, required_unless_present_any = ["resolve_lock_file", "check_lock_file", "batch"])]
+    digest: Option<String>,
+
+    #[clap(long, required_unless_present_any = ["resolve_lock_file", "check_lock_file", "batch"])]
+    architecture: Option<String>,
+
+    /// Instead of pulling, resolve the tag of every image in this lock file to the digest it points at now
+    /// and write the pins back. New images can be added to the lock file without a digest.
+    #[clap(long, conflicts_with = "check_lock_file")]
+    resolve_lock_file: Option<PathBuf>,
+
+    /// Instead of pulling, check every pin in this lock file against what its tag points at now,
+    /// failing with a report of the stale ones.
     #[clap(long)]
-    registry: String,
+    check_lock_file: Option<PathBuf>,
+
+    /// Instead of pulling a single image, pull every image listed in this JSON file concurrently, each into its own
+    /// output directory. Images on the same registry host share connections and tokens.
+    #[clap(long, conflicts_with_all = ["resolve_lock_file", "check_lock_file"])]
+    batch: Option<PathBuf>,
+
+    /// How many images of a batch to pull at the same time.
+    #[clap(long, default_value = "8")]
+    batch_concurrency: usize,
 
+    /// Where to write the config of the image.
+    #[clap(long, default_value = "config.json")]
+    config_path: PathBuf,
+
+    /// Where to write the manifest of the image.
+    #[clap(long, default_value = "manifest.json")]
+    manifest_path: PathBuf,
+
+    /// Write a RemoteMetadata JSON describing the pulled image here, ready to use as the remote_metadata of a merge config.
+    /// Its config and manifest paths are the ones written to, as given.
     #[clap(long)]
-    repository: String,
+    remote_metadata_path: Option<PathBuf>,
 
+    /// Also download every layer, and write the full image as an OCI image layout into this directory.
+    /// The remote metadata then points at it, so merge-app and the pusher take the base layers from it
+    /// instead of the registry.
     #[clap(long)]
-    digest: String,
+    oci_layout: Option<PathBuf>,
 
+    /// Also download every layer, and apply them in order into this directory, giving the filesystem
+    /// a container of the image starts with.
     #[clap(long)]
-    architecture: String,
+    rootfs: Option<PathBuf>,
 
     #[clap(long)]
     // List of comma separated helpers in registry:helper_path format;
@@ -42,27 +104,498 @@ async fn main() -> Result<(), anyhow::Error> {
         Default::default()
     };
 
+    if let Some(lock_file_path) = &opt.resolve_lock_file {
+        return update_lock_file(lock_file_path, docker_authorization_helpers, false).await;
+    }
+    if let Some(lock_file_path) = &opt.check_lock_file {
+        return update_lock_file(lock_file_path, docker_authorization_helpers, true).await;
+    }
+
+    if let Some(batch_path) = &opt.batch {
+        return pull_batch(
+            batch_path,
+            docker_authorization_helpers,
+            opt.batch_concurrency,
+        )
+        .await;
+    }
+
+    let (registry_name, repository, digest) = match (&opt.registry, &opt.repository, &opt.digest) {
+        (Some(registry), Some(repository), Some(digest)) => (registry, repository, digest),
+        _ => bail!("Need a registry, repository and digest to pull"),
+    };
+
     let registry = rules_minidock_tools::registry::from_maybe_domain_and_name(
-        &opt.registry,
-        &opt.repository,
+        registry_name,
+        repository,
         docker_authorization_helpers,
+        Default::default(),
+        Default::default(),
     )
     .await
-    .with_context(|| format!("Failed to connect to registry name: {}", opt.registry))?;
-    let manifest_ret = registry.fetch_manifest_as_string(&opt.digest).await?;
+    .with_context(|| format!("Failed to connect to registry name: {}", registry_name))?;
+    let pulled = pull_image(&registry, digest, None).await?;
+
+    // The layout is filled first, the remote metadata only points at it once it holds every layer.
+    if let Some(oci_layout_path) = &opt.oci_layout {
+        pull_into_oci_layout(
+            &registry,
+            oci_layout_path,
+            digest,
+            &pulled.manifest_content,
+            &pulled.manifest,
+            &pulled.config_content,
+        )
+        .await?;
+    }
+
+    pulled.write_outputs(
+        &opt.config_path,
+        &opt.manifest_path,
+        opt.remote_metadata_path.as_deref(),
+        opt.oci_layout.as_deref(),
+        registry_name,
+        repository,
+    )?;
+
+    if let Some(rootfs_path) = &opt.rootfs {
+        extract_into_rootfs(
+            &registry,
+            rootfs_path,
+            &pulled.manifest,
+            opt.oci_layout.as_deref(),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+struct PulledImage {
+    // Digest of the manifest, the platform specific one when picked out of an image index.
+    digest: String,
+    manifest_content: ContentAndContentType,
+    manifest: Manifest,
+    config_content: ContentAndContentType,
+    config: ConfigDelta,
+}
 
-    let cfg_path = PathBuf::from("config.json");
-    let manifest_path = PathBuf::from("manifest.json");
+impl PulledImage {
+    fn write_outputs(
+        &self,
+        config_path: &Path,
+        manifest_path: &Path,
+        remote_metadata_path: Option<&Path>,
+        oci_layout_path: Option<&Path>,
+        registry_name: &str,
+        repository: &str,
+    ) -> Result<(), anyhow::Error> {
+        self.config.write_file(config_path)?;
 
-    let manifest = Manifest::parse_str(&manifest_ret.content)?;
-    let config_str = registry
+        self.manifest.write_file(manifest_path)?;
+
+        if let Some(remote_metadata_path) = remote_metadata_path {
+            let path_pair = |p: &Path| {
+                let p = p.to_string_lossy().to_string();
+                PathPair {
+                    short_path: p.clone(),
+                    path: p,
+                }
+            };
+            let remote_metadata = RemoteMetadata {
+                config: Some(path_pair(config_path)),
+                manifest: Some(path_pair(manifest_path)),
+                registry: Some(registry_name.to_string()),
+                repository: Some(repository.to_string()),
+                digest: Some(self.digest.clone()),
+                oci_layout: oci_layout_path.map(path_pair),
+            };
+            serde_json::to_writer_pretty(
+                std::io::BufWriter::new(std::fs::File::create(remote_metadata_path)?),
+                &remote_metadata,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Fetches the manifest and config of an image, failing unless they match the digests referring to them.
+// A tag is resolved to the digest it points at first. With a platform, a reference to an image index is
+// followed to the manifest for that platform.
+async fn pull_image(
+    registry: &Arc<dyn Registry>,
+    reference: &str,
+    platform: Option<&str>,
+) -> Result<PulledImage, anyhow::Error> {
+    // Tags are resolved to the digest they point at, that is what gets checked and recorded.
+    let (digest, content) = registry.resolve_manifest(reference).await?;
+    if reference.starts_with("sha256:") && digest != reference {
+        bail!(
+            "Requested manifest {}, but the content the registry sent hashes to {}",
+            reference,
+            digest
+        );
+    }
+    let platforms = lock_file::platform_digests(&content)?;
+    let (reference, manifest_content) = if platforms.is_empty() {
+        (digest, content)
+    } else {
+        let platform = platform.with_context(|| {
+            format!(
+                "{} is an image index, a platform is needed to pick a manifest out of it",
+                digest
+            )
+        })?;
+        let platform_digest = platforms.get(platform).with_context(|| {
+            format!(
+                "Image index {} has no manifest for platform {}, it has: {}",
+                digest,
+                platform,
+                platforms.keys().cloned().collect::<Vec<_>>().join(", ")
+            )
+        })?;
+        (
+            platform_digest.clone(),
+            registry.fetch_manifest_as_string(platform_digest).await?,
+        )
+    };
+
+    let manifest = Manifest::parse_str(&manifest_content.content)?;
+    let config_content = registry
         .fetch_config_as_string(&manifest.config.digest)
         .await?;
 
-    let config = ConfigDelta::parse_str(&config_str.content)?;
+    let config = ConfigDelta::parse_str(&config_content.content)?;
+
+    let problems = integrity::check_pulled_image(
+        &reference,
+        manifest_content.content.as_bytes(),
+        &manifest,
+        config_content.content.as_bytes(),
+        &config,
+    )?;
+    if !problems.is_empty() {
+        bail!(
+            "Image pulled from {} failed integrity checks, not writing any outputs:\n{}",
+            registry.registry_name(),
+            problems.join("\n")
+        );
+    }
+
+    Ok(PulledImage {
+        digest: reference,
+        manifest_content,
+        manifest,
+        config_content,
+        config,
+    })
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct BatchImage {
+    registry: String,
+    repository: String,
+    /// A digest, or a tag which is resolved to the digest it points at.
+    digest: String,
+    /// `os/architecture[/variant]` of the manifest to pull when the digest refers to an image index.
+    #[serde(default)]
+    platform: Option<String>,
+    /// Receives config.json, manifest.json and remote_metadata.json.
+    output_dir: PathBuf,
+}
+
+impl std::fmt::Display for BatchImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}@{}", self.registry, self.repository, self.digest)?;
+        if let Some(platform) = &self.platform {
+            write!(f, " ({})", platform)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Batch {
+    images: Vec<BatchImage>,
+}
+
+// Pulls every image of the batch, reporting on each, and fails if any of them did.
+async fn pull_batch(
+    batch_path: &Path,
+    docker_authorization_helpers: Arc<Vec<DockerAuthenticationHelper>>,
+    concurrency: usize,
+) -> Result<(), anyhow::Error> {
+    let content = std::fs::read_to_string(batch_path)?;
+    let batch: Batch = serde_json::from_str(&content)
+        .with_context(|| format!("Attempting to parse batch file {:?}", batch_path))?;
+
+    let connections = Arc::new(RegistryConnections::new(
+        docker_authorization_helpers,
+        Default::default(),
+        Default::default(),
+    ));
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(concurrency.max(1)));
+
+    let mut tokio_data = Vec::default();
+    for image in batch.images.iter().cloned() {
+        let connections = Arc::clone(&connections);
+        let semaphore = Arc::clone(&semaphore);
+        tokio_data.push(tokio::spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            let registry = connections
+                .connect(&image.registry, &image.repository)
+                .await
+                .with_context(|| {
+                    format!("Failed to connect to registry name: {}", image.registry)
+                })?;
+            let pulled = pull_image(&registry, &image.digest, image.platform.as_deref()).await?;
+
+            std::fs::create_dir_all(&image.output_dir)?;
+            pulled.write_outputs(
+                &image.output_dir.join("config.json"),
+                &image.output_dir.join("manifest.json"),
+                Some(&image.output_dir.join("remote_metadata.json")),
+                None,
+                &image.registry,
+                &image.repository,
+            )?;
+            Ok::<_, anyhow::Error>(pulled.digest)
+        }));
+    }
+
+    let mut failures = 0;
+    for (image, join_result) in batch.images.iter().zip(tokio_data) {
+        match join_result.await? {
+            Ok(reference) => eprintln!(
+                "Pulled {} as {} into {:?}",
+                image, reference, image.output_dir
+            ),
+            Err(e) => {
+                failures += 1;
+                eprintln!("Failed to pull {}: {:#}", image, e);
+            }
+        }
+    }
+    if failures > 0 {
+        bail!(
+            "Failed to pull {} of {} images in {:?}",
+            failures,
+            batch.images.len(),
+            batch_path
+        );
+    }
+    eprintln!("Pulled all {} images", batch.images.len());
+    Ok(())
+}
+
+// Resolves every image in the lock file, then either writes the new pins back or reports the stale ones.
+async fn update_lock_file(
+    lock_file_path: &Path,
+    docker_authorization_helpers: Arc<Vec<DockerAuthenticationHelper>>,
+    check_only: bool,
+) -> Result<(), anyhow::Error> {
+    let lock_file = LockFile::parse_file(lock_file_path)?;
 
-    config.write_file(&cfg_path)?;
+    let mut tokio_data = Vec::default();
+    for image in lock_file.images.iter().cloned() {
+        let docker_authorization_helpers = docker_authorization_helpers.clone();
+        tokio_data.push(tokio::spawn(async move {
+            let registry = rules_minidock_tools::registry::from_maybe_domain_and_name(
+                &image.registry,
+                &image.repository,
+                docker_authorization_helpers,
+                Default::default(),
+                Default::default(),
+            )
+            .await
+            .with_context(|| format!("Failed to connect to registry name: {}", image.registry))?;
+            lock_file::resolve(&image, registry.as_ref()).await
+        }));
+    }
+
+    let mut resolved_images = Vec::default();
+    let mut problems = Vec::default();
+    for (pinned, join_result) in lock_file.images.iter().zip(tokio_data) {
+        match join_result.await? {
+            Ok(resolved) => {
+                if let Some(stale) = lock_file::staleness(pinned, &resolved) {
+                    problems.push(stale);
+                }
+                resolved_images.push(resolved);
+            }
+            Err(e) if check_only => problems.push(format!("{}: {:#}", pinned, e)),
+            Err(e) => return Err(e),
+        }
+    }
+
+    if check_only {
+        if !problems.is_empty() {
+            bail!(
+                "Found {} stale pins in {:?}:\n{}",
+                problems.len(),
+                lock_file_path,
+                problems.join("\n")
+            );
+        }
+        eprintln!("All {} pins are up to date", resolved_images.len());
+    } else {
+        for change in problems.iter() {
+            eprintln!("Updating: {}", change);
+        }
+        LockFile {
+            images: resolved_images,
+        }
+        .write_file(lock_file_path)?;
+    }
+    Ok(())
+}
+
+async fn pull_into_oci_layout(
+    registry: &Arc<dyn Registry>,
+    oci_layout_path: &Path,
+    reference: &str,
+    manifest_content: &ContentAndContentType,
+    manifest: &Manifest,
+    config_content: &ContentAndContentType,
+) -> Result<(), anyhow::Error> {
+    let layout = Arc::new(OciLayout::create(oci_layout_path)?);
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(CONCURRENT_LAYER_DOWNLOADS));
+
+    // Each digest once, two downloads of the same layer would race to the same blob path.
+    let mut tokio_data = Vec::default();
+    let mut downloading = HashSet::new();
+    for layer in manifest.layers.iter() {
+        if !downloading.insert(layer.digest.clone()) {
+            continue;
+        }
+        let layer = layer.clone();
+        let layout = Arc::clone(&layout);
+        let registry = Arc::clone(registry);
+        let semaphore = Arc::clone(&semaphore);
+        tokio_data.push(tokio::spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            if layout.has_blob(&layer.digest).await? {
+                return Ok::<_, anyhow::Error>(());
+            }
+            eprintln!(
+                "Downloading layer {} ({})",
+                layer.digest,
+                size_to_string(layer.size)
+            );
+            let local_storage = layout.temp_blob_file()?;
+            registry
+                .download_blob(local_storage.path(), &layer.digest, layer.size, None)
+                .await
+                .with_context(|| format!("Downloading layer {}", layer.digest))?;
+            local_storage.persist(layout.blob_path(&layer.digest)?)?;
+            Ok(())
+        }));
+    }
+    for join_result in tokio_data {
+        join_result.await??;
+    }
+
+    // The registry content is written as is, re-serializing it would change the digests.
+    layout.write_blob(&manifest.config.digest, config_content.content.as_bytes())?;
+    let manifest_digest = format!(
+        "sha256:{}",
+        Sha256Value::try_from(manifest_content.content.as_bytes())?
+    );
+    layout.write_blob(&manifest_digest, manifest_content.content.as_bytes())?;
+
+    let annotations = if reference.starts_with("sha256:") {
+        None
+    } else {
+        let mut annotations = BTreeMap::default();
+        annotations.insert(REF_NAME_ANNOTATION.to_string(), reference.to_string());
+        Some(annotations)
+    };
+    layout.add_to_index(Descriptor {
+        media_type: manifest_content
+            .content_type
+            .clone()
+            .unwrap_or_else(|| manifest.media_type().to_string()),
+        digest: manifest_digest.clone(),
+        size: manifest_content.content.len() as u64,
+        annotations,
+    })?;
+    eprintln!(
+        "Wrote {} with {} layers to the OCI image layout at {:?}",
+        manifest_digest,
+        manifest.layers.len(),
+        oci_layout_path
+    );
+    Ok(())
+}
+
+// Downloads every distinct layer of manifest into dir, giving the local path of each digest.
+async fn download_layers(
+    registry: &Arc<dyn Registry>,
+    dir: &Path,
+    manifest: &Manifest,
+) -> Result<HashMap<String, PathBuf>, anyhow::Error> {
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(CONCURRENT_LAYER_DOWNLOADS));
+
+    // A layer can be in the manifest more than once, each digest is only downloaded to its file once.
+    let mut tokio_data = Vec::default();
+    let mut downloading = HashSet::new();
+    for layer in manifest.layers.iter() {
+        if !downloading.insert(layer.digest.clone()) {
+            continue;
+        }
+        let layer = layer.clone();
+        let local_path = dir.join(layer.digest.replace("sha256:", ""));
+        let registry = Arc::clone(registry);
+        let semaphore = Arc::clone(&semaphore);
+        tokio_data.push(tokio::spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            eprintln!(
+                "Downloading layer {} ({})",
+                layer.digest,
+                size_to_string(layer.size)
+            );
+            registry
+                .download_blob(&local_path, &layer.digest, layer.size, None)
+                .await
+                .with_context(|| format!("Downloading layer {}", layer.digest))?;
+            Ok::<_, anyhow::Error>((layer.digest, local_path))
+        }));
+    }
+    let mut local_paths = HashMap::new();
+    for join_result in tokio_data {
+        let (digest, local_path) = join_result.await??;
+        local_paths.insert(digest, local_path);
+    }
+    Ok(local_paths)
+}
+
+async fn extract_into_rootfs(
+    registry: &Arc<dyn Registry>,
+    rootfs_path: &Path,
+    manifest: &Manifest,
+    oci_layout_path: Option<&Path>,
+) -> Result<(), anyhow::Error> {
+    // Kept until the layers are extracted, when they had to be downloaded into it.
+    let download_dir = tempfile::tempdir()?;
+    let local_paths = match oci_layout_path {
+        // The layout was just filled with every layer, no need to download them again.
+        Some(oci_layout_path) => {
+            let layout = OciLayout::open(oci_layout_path)?;
+            manifest
+                .layers
+                .iter()
+                .map(|layer| Ok((layer.digest.clone(), layout.blob_path(&layer.digest)?)))
+                .collect::<Result<HashMap<_, _>, anyhow::Error>>()?
+        }
+        None => download_layers(registry, download_dir.path(), manifest).await?,
+    };
+    let layers = rootfs::manifest_layers(manifest, &local_paths)?;
 
-    manifest.write_file(&manifest_path)?;
+    let root = rootfs_path.to_path_buf();
+    tokio::task::spawn_blocking(move || rootfs::extract_layers(&root, &layers)).await??;
+    eprintln!(
+        "Extracted {} layers into {:?}",
+        manifest.layers.len(),
+        rootfs_path
+    );
     Ok(())
 }
@@ -7,25 +7,39 @@ use indicatif::MultiProgress;
 use indicatif::ProgressBar;
 use indicatif::ProgressDrawTarget;
 use indicatif::ProgressStyle;
+use rules_minidock_tools::bundle;
+use rules_minidock_tools::container_specs::annotations;
 use rules_minidock_tools::container_specs::ConfigDelta;
 use rules_minidock_tools::container_specs::Manifest;
 use rules_minidock_tools::container_specs::SpecificationType;
 use rules_minidock_tools::hash::sha256_value::Sha256Value;
+use rules_minidock_tools::preflight;
 
+use rules_minidock_tools::registry::bandwidth::BandwidthLimits;
+use rules_minidock_tools::registry::blob_index::PresentBlobIndex;
+use rules_minidock_tools::registry::metrics::{Metrics, MetricsFormat};
 use rules_minidock_tools::registry::ops::ActionsTaken;
 use rules_minidock_tools::registry::ops::RequestState;
 use rules_minidock_tools::registry::Registry;
+use rules_minidock_tools::registry::TagPolicy;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Parser, Debug)]
 #[clap(name = "pusher app")]
 struct Opt {
-    #[clap(long)]
-    pusher_config: PathBuf,
+    #[clap(
+        long,
+        required_unless_present = "push_bundle",
+        conflicts_with = "push_bundle"
+    )]
+    pusher_config: Option<PathBuf>,
 
     #[clap(long)]
     cache_path: PathBuf,
@@ -37,6 +51,62 @@ struct Opt {
     #[clap(long)]
     skip_manifest_upload: bool,
 
+    /// Push the manifest by digest to every registry first, tags are only moved once that succeeded everywhere.
+    #[clap(long)]
+    two_phase_publish: bool,
+
+    /// When moving tags fails part way through a two phase publish, point the tags that were already moved back at their previous manifests.
+    #[clap(long, requires = "two_phase_publish")]
+    restore_tags_on_failure: bool,
+
+    /// Fail the push instead of moving any tag that already points at a different manifest.
+    #[clap(long)]
+    no_clobber: bool,
+
+    /// After pushing, re-fetch every tag and check every blob on every destination, failing on any mismatch.
+    #[clap(long)]
+    verify: bool,
+
+    /// Finish all the work that doesn't depend on a failed operation, and report every failure at the end.
+    /// Destinations that failed to receive blobs are skipped for the manifest upload.
+    #[clap(long)]
+    keep_going: bool,
+
+    /// File to remember which blobs are present on which registry repositories across runs,
+    /// letting us skip asking the registry about them.
+    #[clap(long)]
+    present_blob_index: Option<PathBuf>,
+
+    /// How long an entry in the present blob index is trusted for.
+    #[clap(long, default_value_t = 24 * 60 * 60)]
+    present_blob_index_ttl_secs: u64,
+
+    /// Instead of pushing, gather everything the push needs into this directory, including base layers from the
+    /// source repository. It can then be pushed with --push-bundle from a network without access to the source registry.
+    #[clap(long, conflicts_with = "push_bundle")]
+    export_bundle: Option<PathBuf>,
+
+    /// Push a bundle written by --export-bundle, it carries its own pusher config.
+    #[clap(long)]
+    push_bundle: Option<PathBuf>,
+
+    /// Maximum bytes per second across all blob transfers, K, M and G suffixes are allowed. e.g. 10M
+    #[clap(long)]
+    max_bandwidth: Option<String>,
+
+    /// Comma separated maximum bytes per second for the blob transfers of individual registries,
+    /// in registry:rate format. e.g. foo.gcr.io:10M,bar.gcr.io:512K
+    #[clap(long)]
+    registry_max_bandwidth: Option<String>,
+
+    /// Write timing, throughput, retry and auth metrics per registry and blob operation to this file when done,
+    /// whether or not the push succeeded.
+    #[clap(long)]
+    metrics_file: Option<PathBuf>,
+
+    #[clap(long, value_enum, default_value_t = MetricsFormat::Json)]
+    metrics_format: MetricsFormat,
+
     #[clap(long)]
     // List of comma separated helpers in registry:helper_path format;
     // requests will attempt to match a helper first based on the "service"
@@ -47,19 +117,42 @@ struct Opt {
     docker_authorization_helpers: Option<String>,
 }
 
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct DestinationConfig {
+    pub registry: String,
+    pub repository: String,
+    /// When unset the top level tags of the pusher config are used.
+    pub container_tags: Option<Vec<String>>,
+}
+
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
 pub struct PusherConfig {
     pub manifest_path: String,
     pub config_path: String,
     pub upload_metadata_path: String,
 
+    #[serde(default)]
     pub registry_list: Vec<String>,
     registry_type: String,
+    #[serde(default)]
     pub repository: String,
+    /// Further registry/repository pairs to push to, on top of the `registry_list` and `repository` ones.
+    #[serde(default)]
+    pub destinations: Vec<DestinationConfig>,
+    /// Repositories on each destination registry, e.g. of known base images, that missing blobs are mounted from when they have them.
+    #[serde(default)]
+    pub mount_from_repositories: Vec<String>,
     pub container_tags: Option<Vec<String>>,
     pub container_tag_file: Option<String>,
     pub stamp_info_file: String,
     pub stamp_to_env: bool,
+    /// Populate the standard org.opencontainers.image.* manifest annotations and config labels
+    /// from the stamp info and the base image metadata.
+    #[serde(default)]
+    pub stamp_to_annotations: bool,
+    /// Regex matching the whole tag, tags matching it are never moved once they exist. e.g. `v\d+\.\d+\.\d+`
+    #[serde(default)]
+    pub immutable_tag_pattern: Option<String>,
 }
 
 impl PusherConfig {
@@ -70,6 +163,44 @@ impl PusherConfig {
             other => bail!("Unknown registry type {}", other),
         }
     }
+
+    pub fn destinations(&self) -> Result<Vec<DestinationConfig>, anyhow::Error> {
+        let mut res: Vec<DestinationConfig> = self
+            .registry_list
+            .iter()
+            .map(|registry| DestinationConfig {
+                registry: registry.clone(),
+                repository: self.repository.clone(),
+                container_tags: None,
+            })
+            .collect();
+        res.extend(self.destinations.iter().cloned());
+
+        if res.is_empty() {
+            bail!("No destinations specified, need at least a registry and repository to push to.")
+        }
+        for destination in res.iter() {
+            if destination.registry.is_empty() {
+                bail!("Passed in an invalid registry, its an empty string.")
+            }
+            if destination.repository.is_empty() {
+                bail!(
+                    "Passed in an invalid repository for registry {}, its an empty string.",
+                    destination.registry
+                )
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[derive(Clone)]
+struct Destination {
+    name: String,
+    registry_host: String,
+    registry: Arc<dyn Registry>,
+    tags: Vec<String>,
+    mount_candidates: Vec<Arc<dyn Registry>>,
 }
 
 fn load_tags(pusher_config: &PusherConfig) -> Result<Vec<String>, anyhow::Error> {
@@ -95,11 +226,31 @@ fn load_tags(pusher_config: &PusherConfig) -> Result<Vec<String>, anyhow::Error>
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     let opt = Opt::parse();
+    let metrics = Arc::new(Metrics::default());
+
+    let result = push(&opt, Arc::clone(&metrics)).await;
 
-    if !opt.pusher_config.exists() {
+    if let Some(metrics_file) = &opt.metrics_file {
+        if let Err(e) = metrics
+            .report()
+            .write_file(metrics_file, opt.metrics_format)
+        {
+            eprintln!("Warning, failed to write metrics: {:#}", e);
+        }
+    }
+    result
+}
+
+async fn push(opt: &Opt, metrics: Arc<Metrics>) -> Result<(), anyhow::Error> {
+    let pusher_config_path = match (&opt.pusher_config, &opt.push_bundle) {
+        (Some(pusher_config_path), _) => pusher_config_path.clone(),
+        (None, Some(bundle_path)) => bundle_path.join(bundle::PUSHER_CONFIG),
+        (None, None) => bail!("Need either a pusher config or a bundle to push"),
+    };
+    if !pusher_config_path.exists() {
         bail!(
             "Path for config passed in does not exist: {:#?}",
-            opt.pusher_config
+            pusher_config_path
         );
     }
 
@@ -109,15 +260,30 @@ async fn main() -> Result<(), anyhow::Error> {
         Default::default()
     };
 
-    let pusher_config_content = std::fs::read_to_string(&opt.pusher_config)?;
-    let pusher_config: PusherConfig = serde_json::from_str(pusher_config_content.as_str())
+    let bandwidth_limits = Arc::new(BandwidthLimits::new(
+        opt.max_bandwidth.as_deref(),
+        opt.registry_max_bandwidth.as_deref(),
+    )?);
+
+    let pusher_config_content = std::fs::read_to_string(&pusher_config_path)?;
+    let mut pusher_config: PusherConfig = serde_json::from_str(pusher_config_content.as_str())
         .with_context(|| {
             format!(
                 "Attempting to pusher config from file: {},content:\n{}",
-                &opt.pusher_config.to_string_lossy(),
+                &pusher_config_path.to_string_lossy(),
                 pusher_config_content
             )
         })?;
+    // Paths in a bundle are relative to it.
+    if let Some(bundle_path) = &opt.push_bundle {
+        for path in [
+            &mut pusher_config.manifest_path,
+            &mut pusher_config.config_path,
+            &mut pusher_config.upload_metadata_path,
+        ] {
+            *path = bundle_path.join(&*path).to_string_lossy().to_string();
+        }
+    }
 
     let config_path = PathBuf::from(&pusher_config.config_path);
 
@@ -128,42 +294,53 @@ async fn main() -> Result<(), anyhow::Error> {
     };
 
     let tags = load_tags(&pusher_config)?;
-    if tags.is_empty() {
-        bail!("No tags specified, unable to know where to push a manifest. Try 'latest' ? ")
-    }
+    let tag_policy = TagPolicy::new(
+        opt.no_clobber,
+        pusher_config.immutable_tag_pattern.as_deref(),
+    )?;
     let upload_metadata_path = PathBuf::from(&pusher_config.upload_metadata_path);
-    let upload_metadata = rules_minidock_tools::UploadMetadata::parse_file(&upload_metadata_path)?;
-
-    let destination_registries_setup: Vec<
-        Result<tokio::task::JoinHandle<Result<Arc<dyn Registry>, anyhow::Error>>, anyhow::Error>,
-    > = pusher_config
-        .registry_list
-        .iter()
-        .map(|r| {
-            let r = r.clone();
-            if r.is_empty() {
-                bail!("Passed in an invalid registry, its an empty string.")
-            }
-            let repository = pusher_config.repository.clone();
-            let docker_authorization_helpers = docker_authorization_helpers.clone();
-            Ok(tokio::spawn(async move {
-                rules_minidock_tools::registry::from_maybe_domain_and_name(
-                    &r,
-                    &repository,
-                    docker_authorization_helpers,
-                )
-                .await
-            }))
-        })
-        .collect();
+    let mut upload_metadata =
+        rules_minidock_tools::UploadMetadata::parse_file(&upload_metadata_path)?;
+    if let Some(bundle_path) = &opt.push_bundle {
+        bundle::resolve_layer_paths(bundle_path, &mut upload_metadata);
+    }
+
+    let preflight_problems =
+        preflight::check_push_inputs(&manifest, &config_path, &upload_metadata).await?;
+    if !preflight_problems.is_empty() {
+        bail!(
+            "Local outputs aren't ready to be pushed, found {} problems:\n{}",
+            preflight_problems.len(),
+            preflight_problems.join("\n")
+        );
+    }
 
-    let mut destination_registries = vec![];
-    for r in destination_registries_setup {
-        destination_registries.push(r?.await??);
+    let mut destination_configs = Vec::default();
+    for destination in pusher_config.destinations()? {
+        let destination_tags = match &destination.container_tags {
+            Some(container_tags) => {
+                let mut container_tags = container_tags.clone();
+                container_tags.sort();
+                container_tags.dedup();
+                container_tags
+            }
+            None => tags.clone(),
+        };
+        if destination_tags.is_empty() {
+            bail!(
+                "No tags specified for {}/{}, unable to know where to push a manifest. Try 'latest' ? ",
+                destination.registry,
+                destination.repository
+            )
+        }
+        destination_configs.push((destination, destination_tags));
     }
 
-    let source_registry = if let Some(source_remote_metadata) =
-        upload_metadata.remote_metadata.as_ref()
+    // With the base image in an oci image layout, its layers are all local and the source isn't needed.
+    let source_registry = if let Some(source_remote_metadata) = upload_metadata
+        .remote_metadata
+        .as_ref()
+        .filter(|r| r.oci_layout.is_none())
     {
         match (
             source_remote_metadata.registry.as_ref(),
@@ -182,7 +359,9 @@ async fn main() -> Result<(), anyhow::Error> {
                 rules_minidock_tools::registry::from_maybe_domain_and_name(
                     &registry,
                     &repository,
-                    docker_authorization_helpers,
+                    docker_authorization_helpers.clone(),
+                    bandwidth_limits.clone(),
+                    metrics.clone(),
                 )
                 .await?,
             ),
@@ -212,6 +391,24 @@ async fn main() -> Result<(), anyhow::Error> {
             config.config = Some(execution_config);
         }
     }
+    if pusher_config.stamp_to_annotations {
+        let stamp_info = std::fs::read_to_string(&pusher_config.stamp_info_file)
+            .map(|content| annotations::parse_stamp_info(&content))
+            .unwrap_or_default();
+        let standard_annotations = annotations::standard_annotations(
+            &stamp_info,
+            upload_metadata.remote_metadata.as_ref(),
+            config.created.as_deref(),
+        );
+
+        let execution_config = config.config.get_or_insert_with(Default::default);
+        let labels = execution_config.labels.get_or_insert_with(Default::default);
+        // The base image usually set these too, they have to describe this image instead.
+        for (k, v) in standard_annotations.iter() {
+            labels.insert(k.clone(), v.clone());
+        }
+        manifest.add_annotations(&standard_annotations);
+    }
     let tmp_config = tempfile::NamedTempFile::new()?;
     config.write_file(tmp_config.path())?;
     let config_path: PathBuf = tmp_config.path().to_path_buf();
@@ -225,6 +422,83 @@ async fn main() -> Result<(), anyhow::Error> {
         local_digests.insert(local_data.outer_sha256.clone(), local_layer_path);
     }
 
+    if let Some(bundle_path) = &opt.export_bundle {
+        return export_bundle(
+            bundle_path,
+            &pusher_config,
+            destination_configs,
+            &manifest,
+            &config_path,
+            &upload_metadata,
+            source_registry.as_ref(),
+        )
+        .await;
+    }
+
+    let mut destinations_setup = Vec::default();
+    for (destination, destination_tags) in destination_configs {
+        let docker_authorization_helpers = docker_authorization_helpers.clone();
+        let bandwidth_limits = bandwidth_limits.clone();
+        let metrics = metrics.clone();
+        let mount_from_repositories = pusher_config.mount_from_repositories.clone();
+        destinations_setup.push(tokio::spawn(async move {
+            let registry = rules_minidock_tools::registry::from_maybe_domain_and_name(
+                &destination.registry,
+                &destination.repository,
+                docker_authorization_helpers.clone(),
+                bandwidth_limits.clone(),
+                metrics.clone(),
+            )
+            .await
+            .with_context(|| {
+                format!(
+                    "Unable to connect to {}/{}",
+                    destination.registry, destination.repository
+                )
+            })?;
+            let mut mount_candidates = Vec::default();
+            for repository in mount_from_repositories
+                .iter()
+                .filter(|r| **r != destination.repository)
+            {
+                match rules_minidock_tools::registry::from_maybe_domain_and_name(
+                    &destination.registry,
+                    repository,
+                    docker_authorization_helpers.clone(),
+                    bandwidth_limits.clone(),
+                    metrics.clone(),
+                )
+                .await
+                {
+                    Ok(r) => mount_candidates.push(r),
+                    Err(e) => eprintln!(
+                        "Warning, unable to use {}/{} to mount blobs from, skipping it: {:#}",
+                        destination.registry, repository, e
+                    ),
+                }
+            }
+            Ok::<_, anyhow::Error>(Destination {
+                name: format!("{}/{}", destination.registry, destination.repository),
+                registry_host: destination.registry,
+                registry,
+                tags: destination_tags,
+                mount_candidates,
+            })
+        }));
+    }
+
+    // Only populated when asked to keep going, otherwise we stop at the first failure.
+    let mut failures: Vec<String> = Vec::default();
+    let mut destinations = vec![];
+    for d in destinations_setup {
+        match d.await? {
+            Ok(destination) => destinations.push(destination),
+            // A registry we can't connect to doesn't hold up the healthy ones.
+            Err(e) if opt.keep_going => failures.push(format!("{:#}", e)),
+            Err(e) => return Err(e),
+        }
+    }
+
     let cache_path = opt.cache_path.join("tmp");
     let tmp_cache_path = opt.cache_path.join("tmp");
     if !tmp_cache_path.exists() {
@@ -239,117 +513,606 @@ async fn main() -> Result<(), anyhow::Error> {
 
     let concurrent_io_operations: &'static Semaphore = Box::leak(Box::new(Semaphore::new(32)));
 
-    let mut tokio_data = Vec::default();
+    // The first destination on each registry host receives the blobs, further repositories on
+    // the same host then only need to mount them from it.
+    let mut first_on_host: HashMap<String, Arc<dyn Registry>> = HashMap::default();
+    let mut waves: [Vec<(Destination, Vec<Arc<dyn Registry>>)>; 2] = Default::default();
+    for destination in destinations.iter() {
+        match first_on_host.get(&destination.registry_host) {
+            Some(first) => {
+                let mut mount_sources = vec![first.clone()];
+                mount_sources.extend(destination.mount_candidates.iter().cloned());
+                waves[1].push((destination.clone(), mount_sources))
+            }
+            None => {
+                first_on_host.insert(
+                    destination.registry_host.clone(),
+                    destination.registry.clone(),
+                );
+                waves[0].push((destination.clone(), destination.mount_candidates.clone()));
+            }
+        }
+    }
+
+    let present_blob_index = opt
+        .present_blob_index
+        .as_ref()
+        .map(|path| {
+            PresentBlobIndex::load(path, Duration::from_secs(opt.present_blob_index_ttl_secs))
+                .map(Arc::new)
+        })
+        .transpose()?;
 
-    for destination_registry in destination_registries.iter().cloned() {
-        let request_state = Arc::new(RequestState {
-            local_digests: local_digests.clone(),
-            destination_registry: Arc::clone(&destination_registry),
-            source_registry: source_registry.clone(),
-            cache_path: cache_path.clone(),
+    // On Ctrl-C we stop the pushes and clean up after them, a second one exits right away.
+    let interrupted = CancellationToken::new();
+    {
+        let interrupted = interrupted.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                eprintln!("\nInterrupted, cleaning up. Press Ctrl-C again to exit immediately.");
+                interrupted.cancel();
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    std::process::exit(130);
+                }
+            }
         });
+    }
 
-        for layer in manifest.layers.iter() {
-            let layer = layer.clone();
-            let request_state = Arc::clone(&request_state);
-            let mp = mp.clone();
+    let mut actions_taken = ActionsTaken::default();
+    let mut failed_destinations: HashSet<String> = HashSet::default();
+    for wave in waves {
+        let mut tokio_data = Vec::default();
 
-            tokio_data.push(tokio::spawn(async move {
-                rules_minidock_tools::registry::ops::ensure_present(
-                    &layer,
-                    request_state,
-                    mp,
-                    concurrent_io_operations,
-                )
-                .await
-            }))
+        for (destination, mount_sources) in wave {
+            let destination_registry = destination.registry.clone();
+            let request_state = Arc::new(RequestState {
+                local_digests: local_digests.clone(),
+                destination_registry: Arc::clone(&destination_registry),
+                source_registry: source_registry.clone(),
+                mount_sources,
+                cache_path: cache_path.clone(),
+                present_blob_index: present_blob_index.clone(),
+            });
+
+            for layer in manifest.layers.iter() {
+                let layer = layer.clone();
+                let request_state = Arc::clone(&request_state);
+                let mp = mp.clone();
+
+                tokio_data.push((
+                    destination.name.clone(),
+                    layer.digest.clone(),
+                    tokio::spawn(async move {
+                        rules_minidock_tools::registry::ops::ensure_present(
+                            &layer,
+                            request_state,
+                            mp,
+                            concurrent_io_operations,
+                        )
+                        .await
+                    }),
+                ))
+            }
+
+            let config_sha_printed = config_sha_printed.clone();
+            let config_path = config_path.clone();
+            let config_upload = tokio::spawn(async move {
+                match destination_registry.blob_exists(&config_sha_printed).await {
+                    Ok(true) => Ok(ActionsTaken::default()),
+                    Err(e) => Err(e),
+                    Ok(false) => destination_registry
+                        .upload_blob(&config_path, &config_sha_printed, config_len.0 as u64, None)
+                        .await
+                        .map(|_| ActionsTaken::default()),
+                }
+            });
+            tokio_data.push((
+                destination.name.clone(),
+                manifest.config.digest.clone(),
+                config_upload,
+            ));
         }
 
-        let config_sha_printed = config_sha_printed.clone();
-        let config_path = config_path.clone();
-        tokio_data.push(tokio::spawn(async move {
-            match destination_registry.blob_exists(&config_sha_printed).await {
-                Ok(true) => Ok(ActionsTaken::default()),
-                Err(e) => Err(e),
-                Ok(false) => destination_registry
-                    .upload_blob(&config_path, &config_sha_printed, config_len.0 as u64, None)
-                    .await
-                    .map(|_| ActionsTaken::default()),
+        let mut tokio_data = VecDeque::from(tokio_data);
+        let mut stop_error = None;
+        while let Some((destination_name, digest, mut join_handle)) = tokio_data.pop_front() {
+            let join_result = tokio::select! {
+                join_result = &mut join_handle => join_result,
+                _ = interrupted.cancelled() => {
+                    tokio_data.push_front((destination_name, digest, join_handle));
+                    stop_error = Some(anyhow::anyhow!("Interrupted while pushing blobs"));
+                    break;
+                }
+            };
+            match join_result? {
+                Ok(a) => actions_taken.merge(&a),
+                Err(e) if opt.keep_going => {
+                    failures.push(format!("{} {}: {:#}", destination_name, digest, e));
+                    failed_destinations.insert(destination_name);
+                }
+                Err(e) => {
+                    stop_error = Some(e);
+                    break;
+                }
             }
-        }));
+        }
+
+        if let Some(e) = stop_error {
+            abandon_blob_pushes(tokio_data, &destinations).await;
+            if let Some(present_blob_index) = &present_blob_index {
+                if let Err(e) = present_blob_index.save() {
+                    eprintln!("Warning, failed to save the present blob index: {:#}", e);
+                }
+            }
+            mp.clear()?;
+            mp.set_draw_target(ProgressDrawTarget::hidden());
+            println!(
+                "\n\nStopped before all layers were ensured present, actions taken:{}\n",
+                actions_taken
+            );
+            return Err(e);
+        }
     }
 
-    let mut actions_taken = ActionsTaken::default();
-    for join_result in tokio_data {
-        actions_taken.merge(&join_result.await??);
+    if let Some(present_blob_index) = &present_blob_index {
+        if let Err(e) = present_blob_index.save() {
+            eprintln!("Warning, failed to save the present blob index: {:#}", e);
+        }
     }
 
-    println!(
-        "\n\nAll referred to layers have been ensured present, actions taken:{}\n",
-        actions_taken
-    );
+    if failures.is_empty() {
+        println!(
+            "\n\nAll referred to layers have been ensured present, actions taken:{}\n",
+            actions_taken
+        );
+    } else {
+        println!(
+            "\n\nFailed to ensure blobs are present, {} failures, actions taken:{}\n",
+            failures.len(),
+            actions_taken
+        );
+        if opt.skip_manifest_upload || opt.two_phase_publish {
+            bail!(
+                "Failed to push blobs, no manifests have been uploaded:\n{}",
+                failures.join("\n")
+            );
+        }
+    }
     if opt.skip_manifest_upload {
         mp.clear()?;
         mp.set_draw_target(ProgressDrawTarget::hidden());
         drop(mp);
 
+        if opt.verify {
+            let problems = verify_destinations(&destinations, &manifest, false).await?;
+            if !problems.is_empty() {
+                bail!(
+                    "Verification of pushed content failed, found {} problems:\n{}",
+                    problems.len(),
+                    problems.join("\n")
+                );
+            }
+        }
         println!("Asked to skip manifest uploads, exiting.");
         return Ok(());
     }
 
+    // Destinations missing blobs can't take the manifest, the healthy ones still get it.
+    let destinations: Vec<Destination> = destinations
+        .into_iter()
+        .filter(|d| !failed_destinations.contains(&d.name))
+        .collect();
+    if destinations.is_empty() {
+        bail!(
+            "Failed to push blobs to every destination:\n{}",
+            failures.join("\n")
+        );
+    }
+
     println!("Manifest uploads commencing");
 
+    let manifest = Arc::new(manifest);
+    let uploaded_locations = if opt.two_phase_publish {
+        two_phase_publish(
+            &destinations,
+            Arc::clone(&manifest),
+            &tag_policy,
+            opt.restore_tags_on_failure,
+        )
+        .await?
+    } else {
+        // A two phase publish is left to finish so it can't leave the tags half moved, plain tag uploads we can stop.
+        tokio::select! {
+            uploaded_locations = upload_tags(
+                &destinations,
+                Arc::clone(&manifest),
+                &tag_policy,
+                &mp,
+                opt.keep_going.then_some(&mut failures),
+            ) => uploaded_locations?,
+            _ = interrupted.cancelled() => {
+                mp.clear()?;
+                bail!("Interrupted while uploading manifests, all blobs had been pushed, actions taken:{}", actions_taken);
+            }
+        }
+    };
+
+    mp.clear()?;
+    mp.set_draw_target(ProgressDrawTarget::hidden());
+    drop(mp);
+
+    for (uploaded_location, tag) in uploaded_locations {
+        if let Some(loc) = uploaded_location {
+            eprintln!("Uploaded {} to: {}", tag, loc);
+        } else {
+            eprintln!(
+                "Skipped an upload, manifest already present for tag {}",
+                tag
+            );
+        }
+    }
+
+    if opt.verify {
+        failures.extend(verify_destinations(&destinations, &manifest, true).await?);
+    }
+
+    if !failures.is_empty() {
+        bail!(
+            "Push finished with {} failures:\n{}",
+            failures.len(),
+            failures.join("\n")
+        );
+    }
+    Ok(())
+}
+
+async fn export_bundle(
+    bundle_path: &Path,
+    pusher_config: &PusherConfig,
+    destination_configs: Vec<(DestinationConfig, Vec<String>)>,
+    manifest: &Manifest,
+    config_path: &Path,
+    upload_metadata: &rules_minidock_tools::UploadMetadata,
+    source_registry: Option<&Arc<dyn Registry>>,
+) -> Result<(), anyhow::Error> {
+    println!("Exporting push bundle to {:?}", bundle_path);
+    let layer_configs =
+        bundle::gather_layers(bundle_path, manifest, upload_metadata, source_registry).await?;
+
+    std::fs::copy(config_path, bundle_path.join(bundle::CONFIG))?;
+    manifest.write_file(bundle_path.join(bundle::MANIFEST))?;
+    let bundle_upload_metadata = rules_minidock_tools::UploadMetadata {
+        layer_configs,
+        remote_metadata: None,
+        duplicate_layers: upload_metadata.duplicate_layers.clone(),
+    };
+    serde_json::to_writer_pretty(
+        std::io::BufWriter::new(std::fs::File::create(
+            bundle_path.join(bundle::UPLOAD_METADATA),
+        )?),
+        &bundle_upload_metadata,
+    )?;
+
+    // Stamping has already been applied to the bundled manifest and config, and every destination gets its tags spelled out.
+    let bundle_pusher_config = PusherConfig {
+        manifest_path: bundle::MANIFEST.to_string(),
+        config_path: bundle::CONFIG.to_string(),
+        upload_metadata_path: bundle::UPLOAD_METADATA.to_string(),
+        registry_list: Vec::default(),
+        registry_type: pusher_config.registry_type.clone(),
+        repository: String::default(),
+        destinations: destination_configs
+            .into_iter()
+            .map(|(destination, tags)| DestinationConfig {
+                container_tags: Some(tags),
+                ..destination
+            })
+            .collect(),
+        mount_from_repositories: pusher_config.mount_from_repositories.clone(),
+        container_tags: None,
+        container_tag_file: None,
+        stamp_info_file: String::default(),
+        stamp_to_env: false,
+        stamp_to_annotations: false,
+        immutable_tag_pattern: pusher_config.immutable_tag_pattern.clone(),
+    };
+    serde_json::to_writer_pretty(
+        std::io::BufWriter::new(std::fs::File::create(
+            bundle_path.join(bundle::PUSHER_CONFIG),
+        )?),
+        &bundle_pusher_config,
+    )?;
+
+    println!(
+        "Exported {} layers, push the bundle with --push-bundle {:?}",
+        manifest.layers.len(),
+        bundle_path
+    );
+    Ok(())
+}
+
+// Destination name, digest and the task ensuring it is present.
+type BlobPush = (
+    String,
+    String,
+    JoinHandle<Result<ActionsTaken, anyhow::Error>>,
+);
+
+// Stops the blob pushes still in flight, waiting on them so their temp files get removed,
+// then cancels the upload sessions they left open.
+async fn abandon_blob_pushes(tokio_data: VecDeque<BlobPush>, destinations: &[Destination]) {
+    for (_, _, join_handle) in tokio_data.iter() {
+        join_handle.abort();
+    }
+    for (_, _, join_handle) in tokio_data {
+        let _ = join_handle.await;
+    }
+
+    for destination in destinations.iter() {
+        match destination.registry.cancel_open_uploads().await {
+            Ok(0) => (),
+            Ok(cancelled) => eprintln!(
+                "Cancelled {} open upload sessions on {}",
+                cancelled, destination.name
+            ),
+            Err(e) => eprintln!(
+                "Warning, failed to cancel open upload sessions on {}: {:#}",
+                destination.name, e
+            ),
+        }
+    }
+}
+
+async fn verify_destinations(
+    destinations: &[Destination],
+    manifest: &Manifest,
+    include_tags: bool,
+) -> Result<Vec<String>, anyhow::Error> {
+    println!("Verifying pushed content");
     let mut tokio_data = Vec::default();
+    for destination in destinations.iter().cloned() {
+        let manifest = manifest.clone();
+        tokio_data.push(tokio::spawn(async move {
+            let tags = if include_tags {
+                destination.tags.clone()
+            } else {
+                Vec::default()
+            };
+            rules_minidock_tools::registry::ops::verify_pushed(
+                &destination.registry,
+                &manifest,
+                &tags,
+            )
+            .await
+            .with_context(|| format!("Verifying push to {}", destination.name))
+        }));
+    }
 
-    // First lets upload the manifest keyed by the digest.
-    let manifest = Arc::new(manifest);
-    for destination_registry in destination_registries.iter() {
-        for t in tags.iter() {
+    let mut problems = Vec::default();
+    for join_result in tokio_data {
+        problems.extend(join_result.await??);
+    }
+    if problems.is_empty() {
+        println!("Verified all destinations");
+    }
+    Ok(problems)
+}
+
+async fn upload_tags(
+    destinations: &[Destination],
+    manifest: Arc<Manifest>,
+    tag_policy: &TagPolicy,
+    mp: &MultiProgress,
+    // When given failing tags are recorded here instead of failing the upload.
+    mut failures: Option<&mut Vec<String>>,
+) -> Result<Vec<(Option<String>, String)>, anyhow::Error> {
+    let mut tokio_data = Vec::default();
+
+    for destination in destinations.iter() {
+        for t in destination.tags.iter() {
             let message_style = ProgressStyle::with_template("{msg}").unwrap();
             let message_pb = ProgressBar::new(1);
             message_pb.set_style(message_style.clone());
             let pb = mp.add(message_pb);
 
-            pb.set_message(format!(
-                "Uploading tag {} to {}",
-                t,
-                destination_registry.registry_name()
-            ));
+            pb.set_message(format!("Uploading tag {} to {}", t, destination.name));
 
+            let destination_name = destination.name.clone();
             let t = t.clone();
-            let destination_registry = destination_registry.clone();
+            let destination_registry = destination.registry.clone();
             let manifest = Arc::clone(&manifest);
+            let tag_policy = tag_policy.clone();
             tokio_data.push(tokio::spawn(async move {
-                let r = destination_registry.upload_manifest(&manifest, &t).await;
+                let r = destination_registry
+                    .upload_manifest(&manifest, &t, &tag_policy)
+                    .await;
                 if r.is_ok() {
                     pb.set_message(format!("{}", console::style("✔").green()));
                 } else {
                     pb.set_message(format!("{}", console::style("x").red()));
                 }
-                r.map(|s| (s, t))
+                (destination_name, t, r)
             }));
         }
     }
 
     let mut uploaded_locations = Vec::default();
     for join_result in tokio_data {
-        let uploaded_location = join_result.await??;
-        uploaded_locations.push(uploaded_location);
+        let (destination_name, t, r) = join_result.await?;
+        match (r, failures.as_mut()) {
+            (Ok(uploaded_location), _) => uploaded_locations.push((uploaded_location, t)),
+            (Err(e), Some(failures)) => {
+                failures.push(format!("{}:{}: {:#}", destination_name, t, e))
+            }
+            (Err(e), None) => return Err(e),
+        }
     }
+    Ok(uploaded_locations)
+}
 
-    mp.clear()?;
-    mp.set_draw_target(ProgressDrawTarget::hidden());
-    drop(mp);
+// Pushes the manifest by digest to every destination, and only once that has succeeded everywhere
+// moves the tags. This way a failing destination can't leave tags pointing at an image
+// that is missing from some of the others.
+async fn two_phase_publish(
+    destinations: &[Destination],
+    manifest: Arc<Manifest>,
+    tag_policy: &TagPolicy,
+    restore_tags_on_failure: bool,
+) -> Result<Vec<(Option<String>, String)>, anyhow::Error> {
+    let manifest_digest = format!(
+        "sha256:{}",
+        Sha256Value::try_from(&manifest.to_bytes()?[..])?
+    );
 
-    for (uploaded_location, tag) in uploaded_locations {
-        if let Some(loc) = uploaded_location {
-            eprintln!("Uploaded {} to: {}", tag, loc);
-        } else {
-            eprintln!(
-                "Skipped an upload, manifest already present for tag {}",
-                tag
-            );
+    let mut tokio_data = Vec::default();
+    for destination in destinations.iter().cloned() {
+        let manifest = Arc::clone(&manifest);
+        let manifest_digest = manifest_digest.clone();
+        tokio_data.push(tokio::spawn(async move {
+            let r = destination
+                .registry
+                .upload_manifest(&manifest, &manifest_digest, &TagPolicy::default())
+                .await;
+            (destination.name, r)
+        }));
+    }
+
+    let mut failures = Vec::default();
+    for join_result in tokio_data {
+        if let (destination_name, Err(e)) = join_result.await? {
+            failures.push(format!("{}: {:#}", destination_name, e));
         }
     }
-    Ok(())
+    if !failures.is_empty() {
+        bail!(
+            "Failed to push manifest {} by digest, no tags have been moved:\n{}",
+            manifest_digest,
+            failures.join("\n")
+        );
+    }
+    eprintln!(
+        "Manifest {} present on all {} destinations, moving tags",
+        manifest_digest,
+        destinations.len()
+    );
+
+    // Snapshot where every tag points right now, so we can refuse to clobber
+    // immutable tags before any of them move, and restore them if asked to.
+    let mut tokio_data = Vec::default();
+    for destination in destinations.iter() {
+        for t in destination.tags.iter().cloned() {
+            let destination_name = destination.name.clone();
+            let destination_registry = destination.registry.clone();
+            tokio_data.push(tokio::spawn(async move {
+                // Only a tag the registry doesn't have is treated as not existing yet. Tags may point
+                // at an image index, so those are accepted too.
+                let previous = destination_registry.try_resolve_manifest(&t).await;
+                (destination_name, destination_registry, t, previous)
+            }));
+        }
+    }
+    let mut snapshots = Vec::default();
+    let mut conflicts = Vec::default();
+    let mut lookup_failures = Vec::default();
+    for join_result in tokio_data {
+        let (destination_name, destination_registry, t, previous) = join_result.await?;
+        let previous = match previous {
+            Ok(previous) => previous,
+            Err(e) => {
+                lookup_failures.push(format!("{}:{}: {:#}", destination_name, t, e));
+                continue;
+            }
+        };
+        if let Some((existing_digest, _)) = previous.as_ref() {
+            if *existing_digest != manifest_digest && !tag_policy.allows_overwrite(&t) {
+                conflicts.push(format!(
+                    "{}:{} already points at {}",
+                    destination_name, t, existing_digest
+                ));
+            }
+        }
+        let previous = previous.map(|(_, content)| content);
+        snapshots.push((destination_name, destination_registry, t, previous));
+    }
+    if !lookup_failures.is_empty() {
+        bail!(
+            "Failed to look up where tags point, no tags have been moved:\n{}",
+            lookup_failures.join("\n")
+        );
+    }
+    if !conflicts.is_empty() {
+        bail!(
+            "Refusing to overwrite immutable tags, no tags have been moved:\n{}",
+            conflicts.join("\n")
+        );
+    }
+
+    let mut tokio_data = Vec::default();
+    for (destination_name, destination_registry, t, previous) in snapshots {
+        let manifest = Arc::clone(&manifest);
+        let tag_policy = tag_policy.clone();
+        tokio_data.push(tokio::spawn(async move {
+            let r = destination_registry
+                .upload_manifest(&manifest, &t, &tag_policy)
+                .await;
+            (destination_name, destination_registry, t, previous, r)
+        }));
+    }
+
+    let mut uploaded_locations = Vec::default();
+    let mut moved = Vec::default();
+    let mut failures = Vec::default();
+    for join_result in tokio_data {
+        let (destination_name, destination_registry, t, previous, r) = join_result.await?;
+        match r {
+            Ok(Some(location)) => {
+                moved.push((destination_name, destination_registry, t.clone(), previous));
+                uploaded_locations.push((Some(location), t));
+            }
+            Ok(None) => uploaded_locations.push((None, t)),
+            Err(e) => failures.push(format!("{}:{}: {:#}", destination_name, t, e)),
+        }
+    }
+
+    if failures.is_empty() {
+        return Ok(uploaded_locations);
+    }
+
+    eprintln!("Failed to move tags:\n{}", failures.join("\n"));
+    if moved.is_empty() {
+        eprintln!("No tags were moved");
+    } else {
+        eprintln!("Tags that were moved to {}:", manifest_digest);
+        for (destination_name, _, t, _) in moved.iter() {
+            eprintln!("  {}:{}", destination_name, t);
+        }
+    }
+
+    if restore_tags_on_failure {
+        for (destination_name, destination_registry, t, previous) in moved.iter() {
+            match previous {
+                Some(previous) => {
+                    match destination_registry
+                        .upload_manifest_content(previous, t)
+                        .await
+                    {
+                        Ok(_) => eprintln!("Restored {}:{}", destination_name, t),
+                        Err(e) => {
+                            eprintln!("Failed to restore {}:{}: {:#}", destination_name, t, e)
+                        }
+                    }
+                }
+                None => eprintln!(
+                    "Unable to restore {}:{}, it did not point at a manifest before",
+                    destination_name, t
+                ),
+            }
+        }
+    }
+
+    bail!(
+        "Failed to move {} tags after pushing manifest {} by digest",
+        failures.len(),
+        manifest_digest
+    )
 }
@@ -0,0 +1,37 @@
+use std::{io::Write, path::Path};
+
+use anyhow::{Context, Error};
+
+/// Writes content to path through a temp file next to it, moved into place once complete, so readers
+/// never see a partial file. The directory holding path is created when missing.
+pub fn write(path: &Path, content: impl AsRef<[u8]>) -> Result<(), Error> {
+    let parent = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    std::fs::create_dir_all(parent)?;
+    let mut tmp_file = tempfile::NamedTempFile::new_in(parent)?;
+    tmp_file.write_all(content.as_ref())?;
+    tmp_file.flush()?;
+    tmp_file
+        .persist(path)
+        .with_context(|| format!("Moving the written file into place at {:?}", path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("nested").join("file.json");
+        write(&path, "first")?;
+        write(&path, "second")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "second");
+        // Only the file itself is left, no temp files next to it.
+        assert_eq!(std::fs::read_dir(dir.path().join("nested"))?.count(), 1);
+        Ok(())
+    }
+}
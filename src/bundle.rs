@@ -0,0 +1,162 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Error};
+
+use crate::{
+    container_specs::Manifest, hash::sha256_value::Sha256Value, registry::Registry, LayerConfig,
+    UploadMetadata,
+};
+
+// A push bundle is a directory holding everything needed to push an image, so the push can
+// happen later from a network that has no access to the source registry.
+pub const PUSHER_CONFIG: &str = "pusher_config.json";
+pub const MANIFEST: &str = "manifest.json";
+pub const CONFIG: &str = "config.json";
+pub const UPLOAD_METADATA: &str = "upload_metadata.json";
+pub const BLOBS_DIR: &str = "blobs";
+
+fn blob_path(digest: &str) -> PathBuf {
+    Path::new(BLOBS_DIR).join(digest.strip_prefix("sha256:").unwrap_or(digest))
+}
+
+// If path holds a blob whose content hashes to digest, a partial or corrupt one left behind doesn't count.
+async fn has_blob(path: &Path, digest: &str) -> Result<bool, Error> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    let (sha, _) = Sha256Value::from_path(path).await?;
+    Ok(format!("sha256:{}", sha) == digest)
+}
+
+/// Puts every layer of the manifest into the blobs directory of the bundle, taken from the local outputs when we
+/// have them and otherwise downloaded from the source registry. Returns the layer configs for the upload metadata
+/// of the bundle, with paths relative to the bundle.
+pub async fn gather_layers(
+    bundle_path: &Path,
+    manifest: &Manifest,
+    upload_metadata: &UploadMetadata,
+    source_registry: Option<&Arc<dyn Registry>>,
+) -> Result<Vec<LayerConfig>, Error> {
+    std::fs::create_dir_all(bundle_path.join(BLOBS_DIR))?;
+
+    let mut layer_configs = Vec::default();
+    for layer in manifest.layers.iter() {
+        let relative_path = blob_path(&layer.digest);
+        let target_path = bundle_path.join(&relative_path);
+        let local_layer = upload_metadata
+            .layer_configs
+            .iter()
+            .find(|l| l.outer_sha256 == layer.digest);
+
+        // Blobs already in the bundle from an earlier export are kept.
+        if !has_blob(&target_path, &layer.digest).await? {
+            if let Some(local_layer) = local_layer {
+                std::fs::copy(&local_layer.layer_data, &target_path).with_context(|| {
+                    format!(
+                        "Copying local layer {} into the bundle at {:?}",
+                        local_layer.layer_data, target_path
+                    )
+                })?;
+            } else if let Some(source_registry) = source_registry {
+                eprintln!(
+                    "Downloading {} from {} into the bundle",
+                    layer.digest,
+                    source_registry.registry_name()
+                );
+                let local_storage = tempfile::NamedTempFile::new_in(bundle_path.join(BLOBS_DIR))?;
+                source_registry
+                    .download_blob(local_storage.path(), &layer.digest, layer.size, None)
+                    .await
+                    .with_context(|| format!("Downloading {} into the bundle", layer.digest))?;
+                local_storage.persist(&target_path)?;
+            } else {
+                bail!(
+                    "Layer {} is not in the local outputs, and there is no source repository to fetch it from",
+                    layer.digest
+                );
+            }
+        }
+
+        layer_configs.push(LayerConfig {
+            layer_data: relative_path.to_string_lossy().to_string(),
+            compressed_length: layer.size,
+            outer_sha256: layer.digest.clone(),
+            // Only the outer digest matters for pushing, so layers from the source repository just repeat it.
+            inner_sha256: local_layer
+                .map(|l| l.inner_sha256.clone())
+                .unwrap_or_else(|| layer.digest.clone()),
+        });
+    }
+    Ok(layer_configs)
+}
+
+/// Rebases the relative paths of layers in a bundles upload metadata onto the bundle directory.
+pub fn resolve_layer_paths(bundle_path: &Path, upload_metadata: &mut UploadMetadata) {
+    for layer_config in upload_metadata.layer_configs.iter_mut() {
+        layer_config.layer_data = bundle_path
+            .join(&layer_config.layer_data)
+            .to_string_lossy()
+            .to_string();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        container_specs::blob_reference::BlobReferenceType, hash::sha256_value::DataLen,
+        test_util::TestDir,
+    };
+
+    #[tokio::test]
+    async fn test_gather_layers() -> Result<(), Error> {
+        let outputs = TestDir::new()?;
+        let bundle = tempfile::tempdir()?;
+
+        let (layer_config, layer_sha, layer_len) =
+            outputs.write_layer("layer.tgz", "layer").await?;
+
+        let mut manifest = Manifest::default();
+        manifest.add_layer(layer_sha, layer_len, BlobReferenceType::LayerGz);
+        let mut upload_metadata = UploadMetadata {
+            layer_configs: vec![layer_config.clone()],
+            ..UploadMetadata::default()
+        };
+
+        let layer_configs = gather_layers(bundle.path(), &manifest, &upload_metadata, None).await?;
+        assert_eq!(layer_configs.len(), 1);
+        assert_eq!(layer_configs[0].inner_sha256, layer_config.inner_sha256);
+
+        let mut bundle_metadata = UploadMetadata {
+            layer_configs,
+            ..UploadMetadata::default()
+        };
+        resolve_layer_paths(bundle.path(), &mut bundle_metadata);
+        assert_eq!(
+            std::fs::read_to_string(&bundle_metadata.layer_configs[0].layer_data)?,
+            "layer"
+        );
+
+        // A blob of the right size but the wrong content, say from an interrupted export, is copied again.
+        std::fs::write(&bundle_metadata.layer_configs[0].layer_data, "LAYER")?;
+        gather_layers(bundle.path(), &manifest, &upload_metadata, None).await?;
+        assert_eq!(
+            std::fs::read_to_string(&bundle_metadata.layer_configs[0].layer_data)?,
+            "layer"
+        );
+
+        // Without a source registry there is nowhere to get a base layer from.
+        let base_sha = Sha256Value::try_from(&b"base"[..])?;
+        manifest.add_layer(base_sha, DataLen(4), BlobReferenceType::LayerGz);
+        upload_metadata.layer_configs.clear();
+        assert!(
+            gather_layers(bundle.path(), &manifest, &upload_metadata, None)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+
+use crate::merge_config::RemoteMetadata;
+
+use super::config::rfc3339_from_unix_seconds;
+
+// Pre-defined annotation keys from the OCI image spec, https://github.com/opencontainers/image-spec/blob/main/annotations.md
+pub const IMAGE_CREATED: &str = "org.opencontainers.image.created";
+pub const IMAGE_SOURCE: &str = "org.opencontainers.image.source";
+pub const IMAGE_VERSION: &str = "org.opencontainers.image.version";
+pub const IMAGE_REVISION: &str = "org.opencontainers.image.revision";
+pub const IMAGE_BASE_DIGEST: &str = "org.opencontainers.image.base.digest";
+pub const IMAGE_BASE_NAME: &str = "org.opencontainers.image.base.name";
+
+// Stamp keys we look for, in order of preference. These cover what bazel itself emits
+// (BUILD_*) along with the usual workspace status script conventions (STABLE_*).
+const REVISION_STAMP_KEYS: &[&str] = &[
+    "STABLE_GIT_COMMIT",
+    "STABLE_BUILD_SCM_REVISION",
+    "BUILD_SCM_REVISION",
+];
+const SOURCE_STAMP_KEYS: &[&str] = &[
+    "STABLE_GIT_REMOTE",
+    "STABLE_BUILD_SCM_REMOTE",
+    "BUILD_SCM_REMOTE",
+];
+const VERSION_STAMP_KEYS: &[&str] = &[
+    "STABLE_VERSION",
+    "STABLE_BUILD_EMBED_LABEL",
+    "BUILD_EMBED_LABEL",
+];
+const CREATED_STAMP_KEYS: &[&str] = &["BUILD_TIMESTAMP"];
+
+/// Parses the `KEY value` lines of a bazel stamp/workspace status file.
+pub fn parse_stamp_info(content: &str) -> BTreeMap<String, String> {
+    content
+        .lines()
+        .filter_map(|ln| ln.split_once(' '))
+        .map(|(k, v)| (k.to_string(), v.trim().to_string()))
+        .collect()
+}
+
+fn first_stamp_value<'a>(
+    stamp_info: &'a BTreeMap<String, String>,
+    keys: &[&str],
+) -> Option<&'a String> {
+    keys.iter()
+        .filter_map(|k| stamp_info.get(*k))
+        .find(|v| !v.is_empty())
+}
+
+/// Builds the standard OCI annotations we can derive from the stamp info and the metadata of the base image.
+/// The creation time of the image config, when it has one, is used over the volatile stamp timestamp, so a
+/// reproducible build keeps giving the same digests.
+pub fn standard_annotations(
+    stamp_info: &BTreeMap<String, String>,
+    base_image: Option<&RemoteMetadata>,
+    config_created: Option<&str>,
+) -> BTreeMap<String, String> {
+    let mut annotations = BTreeMap::default();
+
+    if let Some(revision) = first_stamp_value(stamp_info, REVISION_STAMP_KEYS) {
+        annotations.insert(IMAGE_REVISION.to_string(), revision.clone());
+    }
+    if let Some(source) = first_stamp_value(stamp_info, SOURCE_STAMP_KEYS) {
+        annotations.insert(IMAGE_SOURCE.to_string(), source.clone());
+    }
+    if let Some(version) = first_stamp_value(stamp_info, VERSION_STAMP_KEYS) {
+        annotations.insert(IMAGE_VERSION.to_string(), version.clone());
+    }
+    if let Some(created) = config_created {
+        annotations.insert(IMAGE_CREATED.to_string(), created.to_string());
+    } else if let Some(created) =
+        first_stamp_value(stamp_info, CREATED_STAMP_KEYS).and_then(|e| e.parse::<i64>().ok())
+    {
+        annotations.insert(
+            IMAGE_CREATED.to_string(),
+            rfc3339_from_unix_seconds(created),
+        );
+    }
+
+    if let Some(base_image) = base_image {
+        if let Some(digest) = &base_image.digest {
+            annotations.insert(IMAGE_BASE_DIGEST.to_string(), digest.clone());
+        }
+        if let (Some(registry), Some(repository)) = (&base_image.registry, &base_image.repository) {
+            annotations.insert(
+                IMAGE_BASE_NAME.to_string(),
+                format!("{}/{}", registry, repository),
+            );
+        }
+    }
+
+    annotations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_standard_annotations() {
+        let stamp_info = parse_stamp_info(
+            "BUILD_SCM_REVISION abc123\nSTABLE_GIT_REMOTE https://github.com/foo/bar\nBUILD_EMBED_LABEL \nBUILD_TIMESTAMP 1700000000\n",
+        );
+        let base_image = RemoteMetadata {
+            registry: Some("l.gcr.io".to_string()),
+            repository: Some("google/bazel".to_string()),
+            digest: Some("sha256:0".to_string()),
+            ..RemoteMetadata::default()
+        };
+
+        let annotations = standard_annotations(&stamp_info, Some(&base_image), None);
+
+        let mut expected = BTreeMap::default();
+        expected.insert(IMAGE_REVISION.to_string(), "abc123".to_string());
+        expected.insert(
+            IMAGE_SOURCE.to_string(),
+            "https://github.com/foo/bar".to_string(),
+        );
+        expected.insert(
+            IMAGE_CREATED.to_string(),
+            "2023-11-14T22:13:20Z".to_string(),
+        );
+        expected.insert(IMAGE_BASE_DIGEST.to_string(), "sha256:0".to_string());
+        expected.insert(
+            IMAGE_BASE_NAME.to_string(),
+            "l.gcr.io/google/bazel".to_string(),
+        );
+        assert_eq!(annotations, expected);
+
+        // A creation time in the config wins over the stamp.
+        let annotations =
+            standard_annotations(&stamp_info, Some(&base_image), Some("2020-01-01T00:00:00Z"));
+        assert_eq!(
+            annotations.get(IMAGE_CREATED).map(|c| c.as_str()),
+            Some("2020-01-01T00:00:00Z")
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use super::SpecificationType;
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
@@ -19,4 +21,5 @@ pub struct BlobReference {
     pub specification_type: SpecificationType,
     pub size: u64,
     pub digest: String,
+    pub annotations: Option<BTreeMap<String, String>>,
 }
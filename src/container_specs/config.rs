@@ -1,9 +1,11 @@
-use std::{collections::BTreeMap, path::Path};
+use std::{collections::BTreeMap, path::Path, sync::OnceLock};
 
 use serde::{Deserialize, Serialize};
 
 use anyhow::Error;
 
+use super::merge_directives::{merge_field, replace, strategy_for, MergeDirectives};
+
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Default)]
 
 pub struct ExecutionConfig {
@@ -18,6 +20,8 @@ pub struct ExecutionConfig {
     pub exposed_ports: Option<BTreeMap<String, BTreeMap<String, String>>>,
 
     // Entries are in the format of VARNAME=VARVALUE. These values act as defaults and are merged with any specified when creating a container.
+    // When merging, a later value replaces the earlier one of the same name, ${VARNAME} in it expands to the earlier value,
+    // and an entry of just VARNAME unsets it.
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "Env")]
     pub env: Option<Vec<String>>,
@@ -72,79 +76,195 @@ pub struct ExecutionConfig {
     pub healthcheck: Option<BTreeMap<String, String>>,
 }
 
-impl ExecutionConfig {
-    pub(crate) fn update_with<'a>(
-        &'a mut self,
-        other: &ExecutionConfig,
-    ) -> &'a mut ExecutionConfig {
-        if let Some(e) = &other.user {
-            self.user = Some(e.clone());
-        }
+fn env_name(entry: &str) -> &str {
+    entry.split_once('=').map(|(name, _)| name).unwrap_or(entry)
+}
 
-        if let Some(e) = &other.exposed_ports {
-            self.exposed_ports = Some(e.clone());
-        }
+/// Expands `${VARNAME}` in value to its current value in env, nothing when it isn't set.
+fn expand_env_references(value: &str, env: &[String]) -> String {
+    static REFERENCE: OnceLock<regex::Regex> = OnceLock::new();
+    REFERENCE
+        .get_or_init(|| regex::Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap())
+        .replace_all(value, |captures: &regex::Captures| {
+            env.iter()
+                .find(|e| env_name(e) == &captures[1])
+                .and_then(|e| e.split_once('='))
+                .map(|(_, v)| v.to_string())
+                .unwrap_or_default()
+        })
+        .into_owned()
+}
 
-        if let Some(e) = &other.env {
-            if let Some(our_e) = self.env.as_mut() {
-                our_e.extend_from_slice(e)
-            } else {
-                self.env = Some(e.clone());
+/// Applies updates to env in order, by variable name with the last writer winning.
+fn merge_env(env: &mut Vec<String>, updates: &[String]) {
+    for update in updates.iter() {
+        let (name, value) = match update.split_once('=') {
+            Some(name_and_value) => name_and_value,
+            None => {
+                env.retain(|e| env_name(e) != update);
+                continue;
             }
-        }
-
-        if let Some(e) = &other.entrypoint {
-            self.entrypoint = Some(e.clone());
-        }
-
-        if let Some(e) = &other.cmd {
-            self.cmd = Some(e.clone());
-        }
-
-        if let Some(e) = &other.volumes {
-            if let Some(our_e) = self.volumes.as_mut() {
-                our_e.extend_from_slice(e)
+        };
+        let entry = format!("{}={}", name, expand_env_references(value, env));
+
+        // Replaced where it was set first, any repeats a base image might carry are dropped.
+        let mut replaced = false;
+        env.retain_mut(|e| {
+            if env_name(e) != name {
+                true
+            } else if replaced {
+                false
             } else {
-                self.volumes = Some(e.clone());
+                *e = entry.clone();
+                replaced = true;
+                true
             }
+        });
+        if !replaced {
+            env.push(entry);
         }
+    }
+}
 
-        if let Some(e) = &other.working_dir {
-            self.working_dir = Some(e.clone());
-        }
-
-        if let Some(e) = &other.labels {
-            if let Some(our_e) = self.labels.as_mut() {
-                our_e.extend(e.clone())
-            } else {
-                self.labels = Some(e.clone());
+impl ExecutionConfig {
+    pub(crate) fn update_with<'a>(
+        &'a mut self,
+        other: &ExecutionConfig,
+        directives: Option<&MergeDirectives>,
+    ) -> &'a mut ExecutionConfig {
+        let strategy = |field: &str| strategy_for(directives, field);
+
+        // Tombstones go first, so the delta can add back what it removes.
+        if let Some(directives) = directives {
+            if let Some(labels) = self.labels.as_mut() {
+                for label in directives.remove_labels.iter() {
+                    labels.remove(label);
+                }
+            }
+            if let Some(exposed_ports) = self.exposed_ports.as_mut() {
+                for port in directives.remove_exposed_ports.iter() {
+                    exposed_ports.remove(port);
+                }
+            }
+            if let Some(volumes) = self.volumes.as_mut() {
+                volumes.retain(|v| !directives.remove_volumes.contains(v));
             }
         }
 
-        if let Some(e) = &other.stop_signal {
-            self.stop_signal = Some(e.clone());
-        }
-
-        if let Some(e) = &other.memory {
-            self.memory = Some(*e);
-        }
-
-        if let Some(e) = &other.memory_swap {
-            self.memory_swap = Some(*e);
-        }
-
-        if let Some(e) = &other.cpu_shares {
-            self.cpu_shares = Some(*e);
-        }
-
-        if let Some(e) = &other.healthcheck {
-            self.healthcheck = Some(e.clone());
-        }
+        merge_field(&mut self.user, &other.user, strategy("User"), replace);
+
+        merge_field(
+            &mut self.exposed_ports,
+            &other.exposed_ports,
+            strategy("ExposedPorts"),
+            |ours, theirs| ours.extend(theirs.clone()),
+        );
+
+        merge_field(
+            &mut self.env,
+            &other.env,
+            strategy("Env"),
+            |ours, theirs| merge_env(ours, theirs),
+        );
+
+        merge_field(
+            &mut self.entrypoint,
+            &other.entrypoint,
+            strategy("Entrypoint"),
+            replace,
+        );
+
+        merge_field(&mut self.cmd, &other.cmd, strategy("Cmd"), replace);
+
+        merge_field(
+            &mut self.volumes,
+            &other.volumes,
+            strategy("Volumes"),
+            |ours, theirs| ours.extend_from_slice(theirs),
+        );
+
+        merge_field(
+            &mut self.working_dir,
+            &other.working_dir,
+            strategy("WorkingDir"),
+            replace,
+        );
+
+        merge_field(
+            &mut self.labels,
+            &other.labels,
+            strategy("Labels"),
+            |ours, theirs| ours.extend(theirs.clone()),
+        );
+
+        merge_field(
+            &mut self.stop_signal,
+            &other.stop_signal,
+            strategy("StopSignal"),
+            replace,
+        );
+
+        merge_field(&mut self.memory, &other.memory, strategy("Memory"), replace);
+
+        merge_field(
+            &mut self.memory_swap,
+            &other.memory_swap,
+            strategy("MemorySwap"),
+            replace,
+        );
+
+        merge_field(
+            &mut self.cpu_shares,
+            &other.cpu_shares,
+            strategy("CpuShares"),
+            replace,
+        );
+
+        merge_field(
+            &mut self.healthcheck,
+            &other.healthcheck,
+            strategy("Healthcheck"),
+            |ours, theirs| ours.extend(theirs.clone()),
+        );
 
         self
     }
 }
 
+/// Formats seconds since the unix epoch as an RFC 3339 UTC timestamp, e.g. `1970-01-01T00:00:00Z`.
+pub fn rfc3339_from_unix_seconds(secs: i64) -> String {
+    let days = secs.div_euclid(86400);
+    let secs_of_day = secs.rem_euclid(86400);
+
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        (secs_of_day % 3600) / 60,
+        secs_of_day % 60
+    )
+}
+
+/// If s is an RFC 3339 timestamp, e.g. `2023-11-14T22:13:20Z` or `2023-11-14T23:13:20.5+01:00`.
+pub fn is_rfc3339(s: &str) -> bool {
+    regex::Regex::new(r"^\d{4}-\d{2}-\d{2}[Tt]\d{2}:\d{2}:\d{2}(\.\d+)?([Zz]|[+-]\d{2}:\d{2})$")
+        .unwrap()
+        .is_match(s)
+}
+
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
 
 pub struct HistoryItem {
@@ -237,39 +357,55 @@ impl ConfigDelta {
     }
 
     pub(crate) fn update_with<'a>(&'a mut self, other: &ConfigDelta) -> &'a mut ConfigDelta {
-        if let Some(e) = &other.created {
-            self.created = Some(e.clone());
-        }
-
-        if let Some(e) = &other.author {
-            self.author = Some(e.clone());
-        }
-
-        if let Some(e) = &other.architecture {
-            self.architecture = Some(e.clone());
-        }
-
-        if let Some(e) = &other.os {
-            self.os = Some(e.clone());
-        }
-
-        if let Some(e) = &other.os_version {
-            self.os_version = Some(e.clone());
-        }
-        if let Some(e) = &other.os_features {
-            self.os_features = Some(e.clone());
-        }
-
-        if let Some(e) = &other.variant {
-            self.variant = Some(e.clone());
-        }
-
-        if let Some(e) = &other.config {
-            if let Some(cfg) = self.config.as_mut() {
-                cfg.update_with(e);
-            } else {
-                self.config = Some(e.clone());
-            }
+        let directives = other.merge.as_ref();
+        let strategy = |field: &str| strategy_for(directives, field);
+
+        merge_field(
+            &mut self.created,
+            &other.created,
+            strategy("created"),
+            replace,
+        );
+
+        merge_field(&mut self.author, &other.author, strategy("author"), replace);
+
+        merge_field(
+            &mut self.architecture,
+            &other.architecture,
+            strategy("architecture"),
+            replace,
+        );
+
+        merge_field(&mut self.os, &other.os, strategy("os"), replace);
+
+        merge_field(
+            &mut self.os_version,
+            &other.os_version,
+            strategy("os.version"),
+            replace,
+        );
+        merge_field(
+            &mut self.os_features,
+            &other.os_features,
+            strategy("os.features"),
+            replace,
+        );
+
+        merge_field(
+            &mut self.variant,
+            &other.variant,
+            strategy("variant"),
+            replace,
+        );
+
+        // Directives can remove from the execution config merged into even when the delta has none.
+        if other.config.is_some() || (self.config.is_some() && directives.is_some()) {
+            self.config
+                .get_or_insert_with(ExecutionConfig::default)
+                .update_with(
+                    other.config.as_ref().unwrap_or(&ExecutionConfig::default()),
+                    directives,
+                );
         }
 
         if let Some(e) = &other.rootfs {
@@ -279,13 +415,13 @@ impl ConfigDelta {
                 self.rootfs = Some(e.clone());
             }
         }
-        if let Some(e) = &other.history {
-            if let Some(cfg) = self.history.as_mut() {
-                cfg.extend_from_slice(e);
-            } else {
-                self.history = Some(e.clone());
-            }
-        }
+
+        merge_field(
+            &mut self.history,
+            &other.history,
+            strategy("history"),
+            |ours, theirs| ours.extend_from_slice(theirs),
+        );
 
         self
     }
@@ -326,6 +462,11 @@ pub struct ConfigDelta {
 
     // Describes the history of each layer. The array is ordered from first to last. The object has the following fields:
     pub history: Option<Vec<HistoryItem>>,
+
+    // How this delta merges into the config before it, when the default strategy of a field isn't what's wanted.
+    // Never part of an image config, so it is not written out.
+    #[serde(default, skip_serializing)]
+    pub merge: Option<MergeDirectives>,
 }
 
 impl ConfigDelta {
@@ -373,6 +514,12 @@ impl ConfigDelta {
             unreachable!()
         }
     }
+
+    pub fn add_history(&mut self, history_item: HistoryItem) {
+        self.history
+            .get_or_insert_with(Vec::default)
+            .push(history_item);
+    }
 }
 
 #[cfg(test)]
@@ -407,4 +554,107 @@ mod tests {
         assert_eq!(json0, json1);
         Ok(())
     }
+
+    #[test]
+    fn test_env_merge() {
+        let mut base = ExecutionConfig {
+            env: Some(vec![
+                "PATH=/usr/bin:/bin".to_string(),
+                "HOME=/root".to_string(),
+                "DEBUG=1".to_string(),
+            ]),
+            ..Default::default()
+        };
+        base.update_with(
+            &ExecutionConfig {
+                env: Some(vec![
+                    "PATH=/app/bin:${PATH}".to_string(),
+                    "HOME=/home/app".to_string(),
+                    "DEBUG".to_string(),
+                    "APP_HOME=${HOME}/data${UNSET}".to_string(),
+                ]),
+                ..Default::default()
+            },
+            None,
+        );
+        assert_eq!(
+            base.env.unwrap(),
+            vec![
+                "PATH=/app/bin:/usr/bin:/bin",
+                "HOME=/home/app",
+                "APP_HOME=/home/app/data"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_directives() -> Result<(), Error> {
+        let mut base = ConfigDelta::parse_str(
+            r#"{
+            "author": "base",
+            "config": {
+                "Entrypoint": ["/base"],
+                "Labels": {"maintainer": "base", "keep": "1"},
+                "ExposedPorts": {"8080/tcp": {}, "9090/tcp": {}},
+                "Volumes": ["/data", "/cache"]
+            }
+        }"#,
+        )?;
+        let delta = ConfigDelta::parse_str(
+            r#"{
+            "config": {
+                "Labels": {"team": "app"},
+                "ExposedPorts": {"443/tcp": {}}
+            },
+            "merge": {
+                "strategies": {"Entrypoint": "remove", "ExposedPorts": "append", "author": "remove"},
+                "remove_labels": ["maintainer"],
+                "remove_exposed_ports": ["9090/tcp"],
+                "remove_volumes": ["/cache"]
+            }
+        }"#,
+        )?;
+        base.update_with(&delta);
+
+        assert_eq!(base.author, None);
+        let config = base.config.as_ref().unwrap();
+        assert_eq!(config.entrypoint, None);
+        assert_eq!(
+            config.labels.as_ref().unwrap().keys().collect::<Vec<_>>(),
+            vec!["keep", "team"]
+        );
+        assert_eq!(
+            config
+                .exposed_ports
+                .as_ref()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["443/tcp", "8080/tcp"]
+        );
+        assert_eq!(config.volumes, Some(vec!["/data".to_string()]));
+
+        // The directives only steer the merge, they are not part of the merged config.
+        assert!(!serde_json::to_string(&delta)?.contains("strategies"));
+
+        // Without directives, a delta with nothing set changes nothing.
+        let before = base.clone();
+        base.update_with(&ConfigDelta::default());
+        assert_eq!(base, before);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rfc3339_from_unix_seconds() {
+        assert_eq!(rfc3339_from_unix_seconds(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339_from_unix_seconds(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(
+            rfc3339_from_unix_seconds(1700000000),
+            "2023-11-14T22:13:20Z"
+        );
+        assert!(is_rfc3339(&rfc3339_from_unix_seconds(1700000000)));
+        assert!(is_rfc3339("2023-11-14T23:13:20.5+01:00"));
+        assert!(!is_rfc3339("2023-11-14"));
+        assert!(!is_rfc3339("1700000000"));
+    }
 }
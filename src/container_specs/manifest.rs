@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::{collections::BTreeMap, path::Path};
 
 use super::{
     blob_reference::{BlobReference, BlobReferenceType},
@@ -13,6 +13,7 @@ pub struct Manifest {
     pub specification_type: SpecificationType,
     pub config: BlobReference,
     pub layers: Vec<BlobReference>,
+    pub annotations: Option<BTreeMap<String, String>>,
 }
 
 impl Manifest {
@@ -81,11 +82,8 @@ impl Manifest {
         compressed_sha_v: crate::hash::sha256_value::Sha256Value,
         compressed_size: crate::hash::sha256_value::DataLen,
     ) {
-        self.config = BlobReference {
-            size: compressed_size.0 as u64,
-            digest: format!("sha256:{}", compressed_sha_v),
-            ..self.config
-        };
+        self.config.size = compressed_size.0 as u64;
+        self.config.digest = format!("sha256:{}", compressed_sha_v);
     }
 
     pub fn add_layer(
@@ -99,8 +97,18 @@ impl Manifest {
             specification_type: self.specification_type,
             size: compressed_size.0 as u64,
             digest: format!("sha256:{}", compressed_sha_v),
+            annotations: None,
         });
     }
+
+    pub fn add_annotations(&mut self, annotations: &BTreeMap<String, String>) {
+        if annotations.is_empty() {
+            return;
+        }
+        self.annotations
+            .get_or_insert_with(BTreeMap::default)
+            .extend(annotations.clone());
+    }
 }
 
 #[cfg(test)]
@@ -113,6 +121,7 @@ mod tests {
             specification_type: SpecificationType::Docker,
             size: 1,
             digest: "sha256:0".to_string(),
+            annotations: None,
         }
     }
 
@@ -127,6 +136,7 @@ mod tests {
                 blob(BlobReferenceType::LayerGz),
                 blob(BlobReferenceType::LayerZstd),
             ],
+            annotations: None,
         }
     }
 
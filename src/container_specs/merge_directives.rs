@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Error};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeStrategy {
+    /// The value of the delta takes the place of the one merged into, when the delta has one.
+    Replace,
+    /// The entries of the delta are added to the ones merged into.
+    Append,
+    /// The value merged into is cleared, whatever the delta has.
+    Remove,
+}
+
+// Fields strategies can be given for, by their name in the config, along with the strategy they use by default.
+const FIELD_DEFAULTS: &[(&str, MergeStrategy)] = &[
+    ("created", MergeStrategy::Replace),
+    ("author", MergeStrategy::Replace),
+    ("architecture", MergeStrategy::Replace),
+    ("os", MergeStrategy::Replace),
+    ("os.version", MergeStrategy::Replace),
+    ("os.features", MergeStrategy::Replace),
+    ("variant", MergeStrategy::Replace),
+    ("history", MergeStrategy::Append),
+    ("User", MergeStrategy::Replace),
+    ("ExposedPorts", MergeStrategy::Replace),
+    ("Env", MergeStrategy::Append),
+    ("Entrypoint", MergeStrategy::Replace),
+    ("Cmd", MergeStrategy::Replace),
+    ("Volumes", MergeStrategy::Append),
+    ("WorkingDir", MergeStrategy::Replace),
+    ("Labels", MergeStrategy::Append),
+    ("StopSignal", MergeStrategy::Replace),
+    ("Memory", MergeStrategy::Replace),
+    ("MemorySwap", MergeStrategy::Replace),
+    ("CpuShares", MergeStrategy::Replace),
+    ("Healthcheck", MergeStrategy::Replace),
+];
+
+// Fields holding lists or maps, the only ones there is anything to append to.
+const APPENDABLE_FIELDS: &[&str] = &[
+    "history",
+    "ExposedPorts",
+    "Env",
+    "Volumes",
+    "Labels",
+    "Healthcheck",
+];
+
+/// How a config delta is merged into the config before it, in place of the default strategy of each field.
+/// Only meaningful while merging, it is never written out as part of an image config.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct MergeDirectives {
+    /// Strategy per field, e.g. `{"Entrypoint": "remove", "Labels": "replace"}`.
+    #[serde(default)]
+    pub strategies: BTreeMap<String, MergeStrategy>,
+
+    /// Labels to remove from the ones merged into, before the labels of the delta are added.
+    #[serde(default)]
+    pub remove_labels: Vec<String>,
+
+    /// Exposed ports to remove, e.g. `8080/tcp`.
+    #[serde(default)]
+    pub remove_exposed_ports: Vec<String>,
+
+    /// Volumes to remove.
+    #[serde(default)]
+    pub remove_volumes: Vec<String>,
+}
+
+impl MergeDirectives {
+    /// Rejects strategies for fields we don't know, appending to fields that hold a single value,
+    /// and anything but appending to the history.
+    pub fn check(&self) -> Result<(), Error> {
+        for (field, strategy) in self.strategies.iter() {
+            if !FIELD_DEFAULTS.iter().any(|(f, _)| f == field) {
+                bail!(
+                    "Unknown field {:?} in merge strategies, expected one of: {}",
+                    field,
+                    FIELD_DEFAULTS
+                        .iter()
+                        .map(|(f, _)| *f)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+            if *strategy == MergeStrategy::Append && !APPENDABLE_FIELDS.contains(&field.as_str()) {
+                bail!("{} holds a single value, it can't be appended to", field);
+            }
+            // Every layer keeps its diff_id, dropping history entries would leave them out of step.
+            if field == "history" && *strategy != MergeStrategy::Append {
+                bail!("history can only be appended to, it has to keep an entry for every layer");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The strategy to merge field with, from the directives of the delta when it has any.
+pub(crate) fn strategy_for(directives: Option<&MergeDirectives>, field: &str) -> MergeStrategy {
+    directives
+        .and_then(|d| d.strategies.get(field))
+        .or_else(|| {
+            FIELD_DEFAULTS
+                .iter()
+                .find(|(f, _)| *f == field)
+                .map(|(_, s)| s)
+        })
+        .copied()
+        .unwrap_or(MergeStrategy::Replace)
+}
+
+/// Merges theirs into ours with strategy, append is how the entries of theirs are added to ours.
+pub(crate) fn merge_field<T: Clone + Default>(
+    ours: &mut Option<T>,
+    theirs: &Option<T>,
+    strategy: MergeStrategy,
+    append: impl FnOnce(&mut T, &T),
+) {
+    match (strategy, theirs) {
+        (MergeStrategy::Remove, _) => *ours = None,
+        (MergeStrategy::Replace, Some(theirs)) => *ours = Some(theirs.clone()),
+        (MergeStrategy::Append, Some(theirs)) => {
+            append(ours.get_or_insert_with(T::default), theirs)
+        }
+        (_, None) => (),
+    }
+}
+
+/// Appending for fields holding a single value, the value of the delta takes its place.
+pub(crate) fn replace<T: Clone>(ours: &mut T, theirs: &T) {
+    *ours = theirs.clone();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() -> Result<(), Error> {
+        let directives: MergeDirectives = serde_json::from_str(
+            r#"{"strategies": {"Entrypoint": "remove", "Labels": "replace", "Env": "append"}, "remove_labels": ["a"]}"#,
+        )?;
+        directives.check()?;
+        assert_eq!(
+            strategy_for(Some(&directives), "Entrypoint"),
+            MergeStrategy::Remove
+        );
+        assert_eq!(
+            strategy_for(Some(&directives), "Volumes"),
+            MergeStrategy::Append
+        );
+        assert_eq!(strategy_for(None, "Labels"), MergeStrategy::Append);
+
+        let unknown: MergeDirectives =
+            serde_json::from_str(r#"{"strategies": {"Entrypiont": "remove"}}"#)?;
+        assert!(unknown.check().is_err());
+        let append_to_single_value: MergeDirectives =
+            serde_json::from_str(r#"{"strategies": {"User": "append"}}"#)?;
+        assert!(append_to_single_value.check().is_err());
+        for strategy in ["remove", "replace"] {
+            let history: MergeDirectives = serde_json::from_str(&format!(
+                r#"{{"strategies": {{"history": "{}"}}}}"#,
+                strategy
+            ))?;
+            assert!(history.check().is_err());
+        }
+        let append_history: MergeDirectives =
+            serde_json::from_str(r#"{"strategies": {"history": "append"}}"#)?;
+        append_history.check()?;
+        Ok(())
+    }
+}
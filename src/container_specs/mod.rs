@@ -1,6 +1,8 @@
+pub mod annotations;
 pub mod blob_reference;
 pub mod config;
 pub mod manifest;
+pub mod merge_directives;
 pub mod serde_impl;
 
 pub use config::ConfigDelta;
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 
 use super::{
@@ -18,6 +20,8 @@ impl<'de> Deserialize<'de> for BlobReference {
             pub media_type: String,
             pub size: u64,
             pub digest: String,
+            #[serde(default)]
+            pub annotations: Option<BTreeMap<String, String>>,
         }
         let r = RawVersion::deserialize(deserializer)?;
         let (specification_type, blob_reference_type) = match r.media_type.as_str() {
@@ -58,6 +62,7 @@ impl<'de> Deserialize<'de> for BlobReference {
             specification_type,
             size: r.size,
             digest: r.digest,
+            annotations: r.annotations,
         })
     }
 }
@@ -66,8 +71,8 @@ impl Serialize for BlobReference {
     where
         S: Serializer,
     {
-        // 3 is the number of fields in the struct.
-        let mut state = serializer.serialize_struct("BlobReference", 3)?;
+        let field_count = if self.annotations.is_some() { 4 } else { 3 };
+        let mut state = serializer.serialize_struct("BlobReference", field_count)?;
 
         let media_type = match (&self.specification_type, &self.blob_reference_type) {
             (SpecificationType::Oci, BlobReferenceType::Config) => {
@@ -99,6 +104,9 @@ impl Serialize for BlobReference {
         state.serialize_field("mediaType", media_type)?;
         state.serialize_field("size", &self.size)?;
         state.serialize_field("digest", &self.digest)?;
+        if let Some(annotations) = &self.annotations {
+            state.serialize_field("annotations", annotations)?;
+        }
         state.end()
     }
 }
@@ -117,6 +125,8 @@ impl<'de> Deserialize<'de> for Manifest {
             pub name: Option<String>,
             pub config: BlobReference,
             pub layers: Vec<BlobReference>,
+            #[serde(default)]
+            pub annotations: Option<BTreeMap<String, String>>,
         }
 
         let r = RawVersion::deserialize(deserializer)?;
@@ -145,6 +155,7 @@ impl<'de> Deserialize<'de> for Manifest {
             config: r.config,
             layers: r.layers,
             name: r.name,
+            annotations: r.annotations,
         })
     }
 }
@@ -154,14 +165,17 @@ impl Serialize for Manifest {
     where
         S: Serializer,
     {
-        // 3 is the number of fields in the struct.
-        let mut state = serializer.serialize_struct("Manifest", 4)?;
+        let field_count = if self.annotations.is_some() { 6 } else { 5 };
+        let mut state = serializer.serialize_struct("Manifest", field_count)?;
 
         state.serialize_field("mediaType", self.media_type())?;
         state.serialize_field("schemaVersion", &self.schema_version)?;
         state.serialize_field("config", &self.config)?;
         state.serialize_field("layers", &self.layers)?;
         state.serialize_field("name", &self.name)?;
+        if let Some(annotations) = &self.annotations {
+            state.serialize_field("annotations", annotations)?;
+        }
 
         state.end()
     }
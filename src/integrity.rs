@@ -0,0 +1,109 @@
+use anyhow::Error;
+
+use crate::{
+    container_specs::{ConfigDelta, Manifest},
+    hash::sha256_value::Sha256Value,
+};
+
+/// Checks the manifest and config a registry sent us against the digests referring to them,
+/// so a broken or tampered mirror can't slip us a different image. Returns a description of every mismatch.
+pub fn check_pulled_image(
+    requested_reference: &str,
+    manifest_content: &[u8],
+    manifest: &Manifest,
+    config_content: &[u8],
+    config: &ConfigDelta,
+) -> Result<Vec<String>, Error> {
+    let mut problems = Vec::default();
+
+    // A tag doesn't pin any content, so there is nothing to compare the manifest to.
+    if requested_reference.starts_with("sha256:") {
+        let manifest_digest = format!("sha256:{}", Sha256Value::try_from(manifest_content)?);
+        if manifest_digest != requested_reference {
+            problems.push(format!(
+                "Requested manifest {}, but got content hashing to {}",
+                requested_reference, manifest_digest
+            ));
+        }
+    }
+
+    let config_digest = format!("sha256:{}", Sha256Value::try_from(config_content)?);
+    if config_digest != manifest.config.digest
+        || config_content.len() as u64 != manifest.config.size
+    {
+        problems.push(format!(
+            "Manifest refers to config {} / {} bytes, but got {} / {} bytes",
+            manifest.config.digest,
+            manifest.config.size,
+            config_digest,
+            config_content.len()
+        ));
+    }
+
+    let diff_ids = config
+        .rootfs
+        .as_ref()
+        .and_then(|r| r.diff_ids.as_ref())
+        .map(|d| d.len())
+        .unwrap_or_default();
+    if diff_ids != manifest.layers.len() {
+        problems.push(format!(
+            "Config lists {} diff_ids, but the manifest has {} layers",
+            diff_ids,
+            manifest.layers.len()
+        ));
+    }
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{container_specs::blob_reference::BlobReferenceType, hash::sha256_value::DataLen};
+
+    #[test]
+    fn test_check_pulled_image() -> Result<(), Error> {
+        let mut config = ConfigDelta::default();
+        let layer_sha = Sha256Value::try_from(&b"layer"[..])?;
+        config.add_layer(&layer_sha);
+        let config_content = serde_json::to_vec(&config)?;
+
+        let mut manifest = Manifest::default();
+        manifest.update_config(
+            Sha256Value::try_from(&config_content[..])?,
+            DataLen(config_content.len()),
+        );
+        manifest.add_layer(layer_sha, DataLen(5), BlobReferenceType::LayerGz);
+        let manifest_content = manifest.to_bytes()?;
+        let manifest_digest = format!("sha256:{}", Sha256Value::try_from(&manifest_content[..])?);
+
+        assert!(check_pulled_image(
+            &manifest_digest,
+            &manifest_content,
+            &manifest,
+            &config_content,
+            &config
+        )?
+        .is_empty());
+        assert!(check_pulled_image(
+            "latest",
+            &manifest_content,
+            &manifest,
+            &config_content,
+            &config
+        )?
+        .is_empty());
+
+        // Wrong manifest content, a config that was swapped out, and a layer the config doesn't know about.
+        let problems = check_pulled_image(
+            &manifest_digest,
+            b"{}",
+            &manifest,
+            b"{}",
+            &ConfigDelta::default(),
+        )?;
+        assert_eq!(problems.len(), 3, "{:#?}", problems);
+        Ok(())
+    }
+}
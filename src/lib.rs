@@ -1,8 +1,9 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
 
-use anyhow::Error;
+use anyhow::{Context, Error};
 use clap::Parser;
 use hash::sha256_value::Sha256Value;
 use merge_config::MergeConfig;
@@ -23,10 +24,13 @@ pub struct LayerConfig {
     pub inner_sha256: String,
 }
 
-#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Default)]
 pub struct UploadMetadata {
     pub layer_configs: Vec<LayerConfig>,
     pub remote_metadata: Option<RemoteMetadata>,
+    /// Layers that repeated one already in the image, and what the merge did about them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub duplicate_layers: Vec<merge_outputs::DuplicateLayer>,
 }
 
 impl UploadMetadata {
@@ -64,9 +68,46 @@ pub struct Opt {
 
     #[clap(long)]
     pub upload_metadata_path: PathBuf,
+
+    /// What to do with a layer whose digest is already in the base manifest or was added by an earlier info.
+    #[clap(long, value_enum, default_value_t)]
+    pub duplicate_layer_policy: merge_outputs::DuplicateLayerPolicy,
+
+    /// Seconds since the unix epoch to record as the creation time of the image and its new history entries,
+    /// so the same inputs always give the same config and manifest digests.
+    #[clap(long, env = "SOURCE_DATE_EPOCH")]
+    pub source_date_epoch: Option<i64>,
+
+    /// RFC 3339 timestamp to record as the creation time instead, takes precedence over SOURCE_DATE_EPOCH.
+    #[clap(long)]
+    pub created: Option<String>,
+
+    /// Also apply the layers of the merged image in order into this directory, to look at the filesystem a
+    /// container of it starts with before it is pushed. Base layers are read from the oci image layout the
+    /// remote metadata points at, see `puller-app --oci-layout`.
+    #[clap(long)]
+    pub rootfs: Option<PathBuf>,
+}
+
+impl Opt {
+    fn created(&self) -> Result<Option<String>, Error> {
+        if let Some(created) = &self.created {
+            if !container_specs::config::is_rfc3339(created) {
+                anyhow::bail!(
+                    "--created must be an RFC 3339 timestamp, e.g. 2023-11-14T22:13:20Z, got {:?}",
+                    created
+                );
+            }
+            return Ok(Some(created.clone()));
+        }
+        Ok(self
+            .source_date_epoch
+            .map(container_specs::config::rfc3339_from_unix_seconds))
+    }
 }
 
 pub async fn merge_main(opt: Opt) -> Result<(), anyhow::Error> {
+    let created = opt.created()?;
     let pusher_config = MergeConfig::parse_file(&opt.merger_config_path)?;
 
     let external_execution_config = match &opt.external_config_path {
@@ -89,9 +130,18 @@ pub async fn merge_main(opt: Opt) -> Result<(), anyhow::Error> {
         &pusher_config,
         &relative_search_path,
         external_execution_config,
+        opt.duplicate_layer_policy,
+        created,
     )
     .await?;
 
+    for duplicate in layers.duplicates.iter() {
+        eprintln!(
+            "Layer {} ({}) repeats one added by {}, {:?}",
+            duplicate.layer_data, duplicate.digest, duplicate.duplicate_of, duplicate.action
+        );
+    }
+
     let config_path = opt.config_path;
     merge_config.write_file(&config_path)?;
 
@@ -109,7 +159,18 @@ pub async fn merge_main(opt: Opt) -> Result<(), anyhow::Error> {
     write!(writer, "{}", manifest_sha256)?;
     drop(writer);
 
-    let mut layer_configs = Vec::default();
+    if let Some(rootfs_path) = &opt.rootfs {
+        extract_rootfs(
+            rootfs_path,
+            &manifest,
+            &layers,
+            pusher_config.remote_metadata.as_ref(),
+            &relative_search_path,
+        )?;
+    }
+
+    // Base layers from an oci image layout come first, like they do in the manifest.
+    let mut layer_configs = layers.base_layers;
 
     for output_layer in layers.layers.iter() {
         layer_configs.push(LayerConfig {
@@ -123,6 +184,7 @@ pub async fn merge_main(opt: Opt) -> Result<(), anyhow::Error> {
     let upload_metadata = UploadMetadata {
         layer_configs,
         remote_metadata: pusher_config.remote_metadata.clone(),
+        duplicate_layers: layers.duplicates,
     };
 
     let upload_metadata_path = opt.upload_metadata_path;
@@ -134,9 +196,62 @@ pub async fn merge_main(opt: Opt) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+// Applies every layer of the merged image into rootfs_path, all of them have to be local.
+fn extract_rootfs(
+    rootfs_path: &Path,
+    manifest: &container_specs::Manifest,
+    layers: &merge_outputs::LayerUploads,
+    remote_metadata: Option<&RemoteMetadata>,
+    relative_search_path: &Option<PathBuf>,
+) -> Result<(), Error> {
+    let rel_as_path = |rel: &str| {
+        relative_search_path
+            .as_ref()
+            .map(|p| p.join(rel))
+            .unwrap_or_else(|| PathBuf::from(rel))
+    };
+
+    let mut local_paths = HashMap::new();
+    for output_layer in layers.layers.iter() {
+        local_paths.insert(
+            format!("sha256:{}", output_layer.sha256),
+            rel_as_path(&output_layer.content.path),
+        );
+    }
+    if let Some(oci_layout) = remote_metadata.and_then(|r| r.oci_layout.as_ref()) {
+        let layout = oci_layout::OciLayout::open(rel_as_path(&oci_layout.path))?;
+        for base_layer in layers.base_layers.iter() {
+            local_paths.insert(
+                base_layer.outer_sha256.clone(),
+                layout.blob_path(&base_layer.outer_sha256)?,
+            );
+        }
+    }
+
+    let rootfs_layers = rootfs::manifest_layers(manifest, &local_paths).context(
+        "Base layers only in the registry can't be extracted, pull them with puller-app --oci-layout",
+    )?;
+    rootfs::extract_layers(rootfs_path, &rootfs_layers)?;
+    eprintln!(
+        "Extracted {} layers into {:?}",
+        rootfs_layers.len(),
+        rootfs_path
+    );
+    Ok(())
+}
+
+pub mod atomic_file;
+pub mod bundle;
 pub mod container_specs;
 pub mod hash;
+pub mod integrity;
+pub mod lock_file;
 pub mod merge_config;
 pub mod merge_outputs;
+pub mod oci_layout;
+pub mod preflight;
 
 pub mod registry;
+pub mod rootfs;
+#[cfg(test)]
+mod test_util;
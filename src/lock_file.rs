@@ -0,0 +1,177 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{Context, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::atomic_file;
+use crate::registry::{ContentAndContentType, Registry};
+
+/// A pinned base image. Entries can be added without a digest, resolving fills it in.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct LockedImage {
+    pub registry: String,
+    pub repository: String,
+    pub tag: String,
+    #[serde(default)]
+    pub digest: Option<String>,
+    /// When the tag points at an image index, the digest of the manifest for each `os/architecture[/variant]`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub platforms: BTreeMap<String, String>,
+}
+
+impl std::fmt::Display for LockedImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}:{}", self.registry, self.repository, self.tag)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct LockFile {
+    pub images: Vec<LockedImage>,
+}
+
+impl LockFile {
+    pub fn parse_file(f: impl AsRef<Path>) -> Result<LockFile, Error> {
+        let content = std::fs::read_to_string(f.as_ref())?;
+        serde_json::from_str(&content).with_context(|| {
+            format!(
+                "Attempting to parse lock file: {},content:\n{}",
+                f.as_ref().to_string_lossy(),
+                content
+            )
+        })
+    }
+
+    /// Written atomically, an interrupted resolve leaves the checked in lock file as it was.
+    pub fn write_file(&self, f: impl AsRef<Path>) -> Result<(), Error> {
+        atomic_file::write(f.as_ref(), serde_json::to_vec_pretty(self)?)
+    }
+}
+
+#[derive(Deserialize)]
+struct RawPlatform {
+    os: String,
+    architecture: String,
+    #[serde(default)]
+    variant: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawIndexEntry {
+    digest: String,
+    #[serde(default)]
+    platform: Option<RawPlatform>,
+}
+
+#[derive(Deserialize)]
+struct RawIndex {
+    manifests: Vec<RawIndexEntry>,
+}
+
+/// The manifest digest for each platform of an image index, empty when the content is a plain manifest.
+pub fn platform_digests(
+    content: &ContentAndContentType,
+) -> Result<BTreeMap<String, String>, Error> {
+    let is_index = match content.content_type.as_deref() {
+        Some("application/vnd.oci.image.index.v1+json")
+        | Some("application/vnd.docker.distribution.manifest.list.v2+json") => true,
+        Some(_) => false,
+        // Without a content type, an index is whatever has a manifests list.
+        None => content.content.contains("\"manifests\""),
+    };
+    if !is_index {
+        return Ok(BTreeMap::default());
+    }
+    let index: RawIndex =
+        serde_json::from_str(&content.content).context("Attempting to parse image index")?;
+    Ok(index
+        .manifests
+        .into_iter()
+        .filter_map(|entry| {
+            let platform = entry.platform?;
+            let key = match platform.variant {
+                Some(variant) => format!("{}/{}/{}", platform.os, platform.architecture, variant),
+                None => format!("{}/{}", platform.os, platform.architecture),
+            };
+            Some((key, entry.digest))
+        })
+        .collect())
+}
+
+/// Looks up what the tag of the image currently points at.
+pub async fn resolve(image: &LockedImage, registry: &dyn Registry) -> Result<LockedImage, Error> {
+    let (digest, content) = registry
+        .resolve_manifest(&image.tag)
+        .await
+        .with_context(|| format!("Resolving {}", image))?;
+    Ok(LockedImage {
+        digest: Some(digest),
+        platforms: platform_digests(&content)?,
+        ..image.clone()
+    })
+}
+
+/// Describes how a pin differs from what its tag resolves to now, or None when it is up to date.
+pub fn staleness(pinned: &LockedImage, resolved: &LockedImage) -> Option<String> {
+    if pinned.digest == resolved.digest && pinned.platforms == resolved.platforms {
+        return None;
+    }
+    Some(format!(
+        "{} is pinned to {}, but now resolves to {}",
+        pinned,
+        pinned.digest.as_deref().unwrap_or("nothing"),
+        resolved.digest.as_deref().unwrap_or("nothing")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_platform_digests() -> Result<(), Error> {
+        let index = ContentAndContentType {
+            content_type: Some("application/vnd.oci.image.index.v1+json".to_string()),
+            content: r#"{"schemaVersion":2,"manifests":[
+                {"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:a","size":1,"platform":{"os":"linux","architecture":"amd64"}},
+                {"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:b","size":1,"platform":{"os":"linux","architecture":"arm64","variant":"v8"}},
+                {"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:c","size":1}
+            ]}"#
+            .to_string(),
+        };
+        let platforms = platform_digests(&index)?;
+        assert_eq!(platforms.len(), 2);
+        assert_eq!(platforms["linux/amd64"], "sha256:a");
+        assert_eq!(platforms["linux/arm64/v8"], "sha256:b");
+
+        let manifest = ContentAndContentType {
+            content_type: Some("application/vnd.oci.image.manifest.v1+json".to_string()),
+            content: "{}".to_string(),
+        };
+        assert!(platform_digests(&manifest)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_staleness() {
+        let pinned = LockedImage {
+            registry: "l.gcr.io".to_string(),
+            repository: "google/bazel".to_string(),
+            tag: "latest".to_string(),
+            digest: Some("sha256:a".to_string()),
+            platforms: BTreeMap::default(),
+        };
+        assert_eq!(staleness(&pinned, &pinned.clone()), None);
+        let resolved = LockedImage {
+            digest: Some("sha256:b".to_string()),
+            ..pinned.clone()
+        };
+        assert_eq!(
+            staleness(&pinned, &resolved),
+            Some(
+                "l.gcr.io/google/bazel:latest is pinned to sha256:a, but now resolves to sha256:b"
+                    .to_string()
+            )
+        );
+    }
+}
@@ -8,26 +8,26 @@ use crate::container_specs::config::ConfigDelta;
 
 use super::PathPair;
 
-#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
-pub struct HistoryItem {
-    pub author: Option<String>,
-    pub created: String,
-    pub created_by: String,
-}
-
-#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Default)]
 pub struct RemoteMetadata {
     pub config: Option<PathPair>,
     pub manifest: Option<PathPair>,
     pub registry: Option<String>,
     pub repository: Option<String>,
     pub digest: Option<String>,
+    /// OCI image layout holding the layers of the base image, e.g. written by `puller-app --oci-layout`.
+    /// Its layers are then pushed from disk, rather than fetched from the registry above.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oci_layout: Option<PathPair>,
 }
 
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
 pub struct Info {
     pub data: Option<PathPair>,
     pub config: Option<ConfigDelta>,
+    /// What produced this info, recorded as `created_by` in the history of the image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
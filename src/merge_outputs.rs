@@ -1,13 +1,19 @@
 use crate::{
     container_specs::{self, ConfigDelta, Manifest},
     hash::sha256_value::{DataLen, Sha256Value},
-    PathPair,
+    oci_layout::{self, OciLayout},
+    LayerConfig, PathPair,
 };
 
-use crate::container_specs::config::ExecutionConfig;
-use std::{collections::HashMap, io::Read, path::PathBuf};
+use crate::container_specs::config::{ExecutionConfig, HistoryItem};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    path::PathBuf,
+};
 
-use anyhow::bail;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
 
 fn detect_compression_type(path: &std::path::Path) -> container_specs::blob_reference::BlobReferenceType {
     // First, try to detect compression by reading file magic bytes
@@ -53,6 +59,9 @@ fn detect_compression_type(path: &std::path::Path) -> container_specs::blob_refe
     }
 }
 
+// created_by of history entries for infos that only change the config, unless they have a description.
+const CONFIG_ONLY_CREATED_BY: &str = "minidock merge config";
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct OutputLayer {
     pub content: PathPair,
@@ -62,15 +71,96 @@ pub struct OutputLayer {
     pub uncompressed_size: DataLen,
 }
 
+/// What to do with a layer whose digest is already in the image, from the base manifest or an earlier info.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateLayerPolicy {
+    /// Fail the merge.
+    Error,
+    /// Leave the repeated layer out of the image.
+    Skip,
+    /// Add the layer again. Applying it a second time restores any of its files changed by layers in between.
+    #[default]
+    Keep,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DuplicateLayer {
+    pub digest: String,
+    pub layer_data: String,
+    /// The short path of the info that added it first, or `base manifest`.
+    pub duplicate_of: String,
+    pub action: DuplicateLayerPolicy,
+}
+
 #[derive(Default, Debug, PartialEq, Eq)]
 pub struct LayerUploads {
     pub layers: Vec<OutputLayer>,
+    pub duplicates: Vec<DuplicateLayer>,
+    /// Layers of the base image found in its OCI image layout, when the remote metadata points at one.
+    pub base_layers: Vec<LayerConfig>,
+}
+
+// The base image layers in the layout at oci_layout, checked against the base manifest and config.
+// Their content was verified against the digests when the puller wrote them.
+fn base_layers_from_oci_layout(
+    oci_layout: &PathPair,
+    layout_path: &std::path::Path,
+    manifest: &Manifest,
+    cfg: &ConfigDelta,
+) -> Result<Vec<LayerConfig>, anyhow::Error> {
+    let layout = OciLayout::open(layout_path)?;
+    let diff_ids = cfg
+        .rootfs
+        .as_ref()
+        .and_then(|r| r.diff_ids.clone())
+        .unwrap_or_default();
+    if diff_ids.len() != manifest.layers.len() {
+        bail!(
+            "The base config has {} diff_ids, but the base manifest has {} layers",
+            diff_ids.len(),
+            manifest.layers.len()
+        );
+    }
+
+    let mut base_layers = Vec::default();
+    for (layer, diff_id) in manifest.layers.iter().zip(diff_ids) {
+        let blob_path = layout.blob_path(&layer.digest)?;
+        let size = std::fs::metadata(&blob_path)
+            .with_context(|| {
+                format!(
+                    "Base layer {} is missing from the oci image layout at {:?}",
+                    layer.digest, layout_path
+                )
+            })?
+            .len();
+        if size != layer.size {
+            bail!(
+                "Base layer {} in the oci image layout is {} bytes, the base manifest expects {}",
+                layer.digest,
+                size,
+                layer.size
+            );
+        }
+        base_layers.push(LayerConfig {
+            layer_data: std::path::Path::new(&oci_layout.short_path)
+                .join(oci_layout::blob_relative_path(&layer.digest)?)
+                .to_string_lossy()
+                .to_string(),
+            compressed_length: layer.size,
+            outer_sha256: layer.digest.clone(),
+            inner_sha256: diff_id,
+        });
+    }
+    Ok(base_layers)
 }
 
 pub async fn merge(
     merge_config: &super::merge_config::MergeConfig,
     relative_search_path: &Option<PathBuf>,
     external_execution_configs: Vec<ExecutionConfig>,
+    duplicate_layer_policy: DuplicateLayerPolicy,
+    created: Option<String>,
 ) -> Result<
     (
         container_specs::ConfigDelta,
@@ -113,6 +203,20 @@ pub async fn merge(
                 )
             }
             manifest = Manifest::parse_file(&p)?;
+            // Manifest level annotations describe the base image itself, not what we build on top of it.
+            manifest.annotations = None;
+        }
+
+        if let Some(oci_layout) = &remote_info.oci_layout {
+            if remote_info.manifest.is_none() {
+                bail!("The remote metadata points at an oci image layout, but has no base manifest to find the layers in it");
+            }
+            layer_uploads.base_layers = base_layers_from_oci_layout(
+                oci_layout,
+                &rel_as_path(&oci_layout.path),
+                &manifest,
+                &cfg,
+            )?;
         }
     }
 
@@ -124,12 +228,16 @@ pub async fn merge(
     }
 
     let mut pass_one_data = Vec::default();
+    let mut hashed_paths = HashSet::new();
     for info in merge_config.infos.iter() {
         if let Some(layer) = &info.data {
             let pb = rel_as_path(&layer.path);
             if !pb.exists() {
                 bail!("Layer path likely incorrect, unable to find {:?}", pb)
             }
+            if !hashed_paths.insert(pb.clone()) {
+                continue;
+            }
 
             let pb2 = pb.clone();
             let sp = tokio::spawn(async {
@@ -174,10 +282,38 @@ pub async fn merge(
         cfg.update_with(&external_base_cfg);
     }
 
+    // Where each digest in the image came from.
+    let mut added_by: HashMap<String, String> = manifest
+        .layers
+        .iter()
+        .map(|l| (l.digest.clone(), "base manifest".to_string()))
+        .collect();
+
     for info in merge_config.infos.iter() {
         if let Some(config) = &info.config {
+            if let Some(directives) = &config.merge {
+                directives.check()?;
+            }
             cfg.update_with(config);
         }
+        // Without an explicit creation time, entries carry the one the image has so far.
+        let entry_created = created.clone().or_else(|| cfg.created.clone());
+        let history_item = |created_by: &str, empty_layer: Option<bool>| HistoryItem {
+            created: entry_created.clone(),
+            author: None,
+            created_by: Some(
+                info.description
+                    .as_deref()
+                    .unwrap_or(created_by)
+                    .to_string(),
+            ),
+            comment: None,
+            empty_layer,
+        };
+        // Infos only changing the config, or whose layer was skipped, still get an entry, just without a layer.
+        if info.data.is_none() && info.config.is_some() {
+            cfg.add_history(history_item(CONFIG_ONLY_CREATED_BY, Some(true)));
+        }
         if let Some(layer) = &info.data {
             let pb = rel_as_path(&layer.path);
             if !pb.exists() {
@@ -191,8 +327,26 @@ pub async fn merge(
                 lut_data.get(&(pb.clone(), false)).ok_or_else(|| {
                     anyhow::anyhow!("Unable to find data in map. shouldn't be possible")
                 })?;
-            let _sha_str_fmt = format!("sha256:{}", inner_sha_v);
+            let digest = format!("sha256:{}", compressed_sha_v);
+            if let Some(duplicate_of) = added_by.get(&digest) {
+                layer_uploads.duplicates.push(DuplicateLayer {
+                    digest: digest.clone(),
+                    layer_data: layer.short_path.clone(),
+                    duplicate_of: duplicate_of.clone(),
+                    action: duplicate_layer_policy,
+                });
+                if duplicate_layer_policy == DuplicateLayerPolicy::Skip {
+                    if info.config.is_some() {
+                        cfg.add_history(history_item(CONFIG_ONLY_CREATED_BY, Some(true)));
+                    }
+                    continue;
+                }
+            } else {
+                added_by.insert(digest, layer.short_path.clone());
+            }
+
             cfg.add_layer(inner_sha_v);
+            cfg.add_history(history_item(&layer.short_path, None));
 
             let compression_type = detect_compression_type(&pb);
             manifest.add_layer(
@@ -210,12 +364,34 @@ pub async fn merge(
         }
     }
 
+    if duplicate_layer_policy == DuplicateLayerPolicy::Error && !layer_uploads.duplicates.is_empty()
+    {
+        bail!(
+            "Found layers repeating ones already in the image:\n{}",
+            layer_uploads
+                .duplicates
+                .iter()
+                .map(|d| format!(
+                    "{} ({}) duplicates {}",
+                    d.layer_data, d.digest, d.duplicate_of
+                ))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+
+    // An explicit creation time wins over whatever the base image or infos carry.
+    if created.is_some() {
+        cfg.created = created;
+    }
+
     Ok((cfg, manifest, layer_uploads))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::TestDir;
     use std::io::Write;
     use tempfile::NamedTempFile;
 
@@ -339,4 +515,194 @@ mod tests {
         let result = detect_compression_type(temp.path());
         assert_eq!(result, container_specs::blob_reference::BlobReferenceType::LayerZstd);
     }
+
+    async fn merge_with(
+        merge_config: &crate::merge_config::MergeConfig,
+        duplicate_layer_policy: DuplicateLayerPolicy,
+        created: Option<&str>,
+    ) -> Result<(ConfigDelta, Manifest, LayerUploads), anyhow::Error> {
+        merge(
+            merge_config,
+            &None,
+            Vec::default(),
+            duplicate_layer_policy,
+            created.map(|c| c.to_string()),
+        )
+        .await
+    }
+
+    fn base_manifest(layers: &[(Sha256Value, DataLen)]) -> Manifest {
+        let mut manifest = Manifest {
+            schema_version: 2,
+            ..Manifest::default()
+        };
+        for (sha, len) in layers.iter() {
+            manifest.add_layer(
+                *sha,
+                *len,
+                container_specs::blob_reference::BlobReferenceType::Layer,
+            );
+        }
+        manifest
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_layers() -> Result<(), anyhow::Error> {
+        use crate::merge_config::{Info, MergeConfig, RemoteMetadata};
+
+        let dir = TestDir::new()?;
+        let (_, b_sha, b_len) = dir.write_layer("b.tar", "b").await?;
+        dir.write("a.tar", "a")?;
+        base_manifest(&[(b_sha, b_len)]).write_file(dir.path().join("base_manifest.json"))?;
+
+        let info = |name: &str| Info {
+            data: Some(dir.path_pair(name)),
+            config: None,
+            description: None,
+        };
+        let merge_config = MergeConfig {
+            infos: vec![info("a.tar"), info("a.tar"), info("b.tar")],
+            remote_metadata: Some(RemoteMetadata {
+                manifest: Some(dir.path_pair("base_manifest.json")),
+                ..RemoteMetadata::default()
+            }),
+        };
+
+        let (cfg, manifest, layers) =
+            merge_with(&merge_config, DuplicateLayerPolicy::Skip, None).await?;
+        assert_eq!(manifest.layers.len(), 2);
+        assert_eq!(layers.layers.len(), 1);
+        assert_eq!(cfg.rootfs.unwrap().diff_ids.unwrap().len(), 1);
+        assert_eq!(
+            layers
+                .duplicates
+                .iter()
+                .map(|d| (d.layer_data.as_str(), d.duplicate_of.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("a.tar", "a.tar"), ("b.tar", "base manifest")]
+        );
+
+        let (_, manifest, _) = merge_with(&merge_config, DuplicateLayerPolicy::Keep, None).await?;
+        assert_eq!(manifest.layers.len(), 4);
+
+        assert!(merge_with(&merge_config, DuplicateLayerPolicy::Error, None)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_base_layers_from_oci_layout() -> Result<(), anyhow::Error> {
+        use crate::merge_config::{MergeConfig, RemoteMetadata};
+
+        let dir = TestDir::new()?;
+        let layout = OciLayout::create(dir.path().join("layout"))?;
+        let layer = b"base layer";
+        let (layer_sha, layer_len) = (Sha256Value::try_from(&layer[..])?, DataLen(layer.len()));
+        let layer_digest = format!("sha256:{}", layer_sha);
+        layout.write_blob(&layer_digest, layer)?;
+
+        base_manifest(&[(layer_sha, layer_len)])
+            .write_file(dir.path().join("base_manifest.json"))?;
+        let inner_sha = Sha256Value::try_from(&b"inner"[..])?;
+        let mut base_config = ConfigDelta::default();
+        base_config.add_layer(&inner_sha);
+        base_config.write_file(dir.path().join("base_config.json"))?;
+
+        let mut merge_config = MergeConfig {
+            infos: Vec::default(),
+            remote_metadata: Some(RemoteMetadata {
+                config: Some(dir.path_pair("base_config.json")),
+                manifest: Some(dir.path_pair("base_manifest.json")),
+                oci_layout: Some(dir.path_pair("layout")),
+                ..RemoteMetadata::default()
+            }),
+        };
+        let (_, _, layers) = merge_with(&merge_config, DuplicateLayerPolicy::Keep, None).await?;
+        assert_eq!(
+            layers.base_layers,
+            vec![LayerConfig {
+                layer_data: format!("layout/blobs/sha256/{}", layer_sha),
+                compressed_length: layer.len() as u64,
+                outer_sha256: layer_digest.clone(),
+                inner_sha256: format!("sha256:{}", inner_sha),
+            }]
+        );
+
+        // A layout missing a layer of the base image can't stand in for the registry.
+        std::fs::remove_file(layout.blob_path(&layer_digest)?)?;
+        assert!(merge_with(&merge_config, DuplicateLayerPolicy::Keep, None)
+            .await
+            .is_err());
+
+        merge_config.remote_metadata.as_mut().unwrap().oci_layout = Some(dir.path_pair("missing"));
+        assert!(merge_with(&merge_config, DuplicateLayerPolicy::Keep, None)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_history() -> Result<(), anyhow::Error> {
+        use crate::merge_config::{Info, MergeConfig};
+
+        let dir = TestDir::new()?;
+        let merge_config = MergeConfig {
+            infos: vec![
+                Info {
+                    data: None,
+                    config: Some(ConfigDelta {
+                        created: Some("2020-01-01T00:00:00Z".to_string()),
+                        ..ConfigDelta::default()
+                    }),
+                    description: Some("set the env".to_string()),
+                },
+                Info {
+                    data: Some(dir.write("a.tar", "a")?),
+                    config: None,
+                    description: None,
+                },
+                Info {
+                    data: Some(dir.write("b.tar", "b")?),
+                    config: None,
+                    description: Some("//app:b".to_string()),
+                },
+            ],
+            remote_metadata: None,
+        };
+        let (cfg, _, _) = merge_with(
+            &merge_config,
+            DuplicateLayerPolicy::Keep,
+            Some("2023-11-14T22:13:20Z"),
+        )
+        .await?;
+        assert_eq!(cfg.created.as_deref(), Some("2023-11-14T22:13:20Z"));
+
+        let history = cfg.history.unwrap();
+        assert_eq!(
+            history
+                .iter()
+                .map(|h| (h.created_by.as_deref().unwrap(), h.empty_layer))
+                .collect::<Vec<_>>(),
+            vec![
+                ("set the env", Some(true)),
+                ("a.tar", None),
+                ("//app:b", None)
+            ]
+        );
+        assert!(history
+            .iter()
+            .all(|h| h.created.as_deref() == Some("2023-11-14T22:13:20Z")));
+        // Every entry that isn't empty lines up with a diff_id.
+        assert_eq!(cfg.rootfs.unwrap().diff_ids.unwrap().len(), 2);
+
+        // Without an explicit creation time, entries get the one the image carries.
+        let (cfg, _, _) = merge_with(&merge_config, DuplicateLayerPolicy::Keep, None).await?;
+        assert!(cfg
+            .history
+            .unwrap()
+            .iter()
+            .all(|h| h.created.as_deref() == Some("2020-01-01T00:00:00Z")));
+        Ok(())
+    }
 }
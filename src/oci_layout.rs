@@ -0,0 +1,190 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::{atomic_file, hash::sha256_value::Sha256Value};
+
+// https://github.com/opencontainers/image-spec/blob/main/image-layout.md
+pub const OCI_LAYOUT_FILE: &str = "oci-layout";
+pub const INDEX_FILE: &str = "index.json";
+pub const INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+pub const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct Descriptor {
+    #[serde(rename = "mediaType")]
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct ImageIndex {
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: u16,
+    #[serde(rename = "mediaType", default)]
+    pub media_type: Option<String>,
+    pub manifests: Vec<Descriptor>,
+}
+
+impl Default for ImageIndex {
+    fn default() -> Self {
+        ImageIndex {
+            schema_version: 2,
+            media_type: Some(INDEX_MEDIA_TYPE.to_string()),
+            manifests: Vec::default(),
+        }
+    }
+}
+
+/// Path of the blob with digest, relative to the root of a layout.
+pub fn blob_relative_path(digest: &str) -> Result<PathBuf, Error> {
+    let hex = match digest.strip_prefix("sha256:") {
+        Some(hex) if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) => hex,
+        _ => bail!("Unsupported or invalid digest {:?}", digest),
+    };
+    Ok(Path::new("blobs").join("sha256").join(hex))
+}
+
+/// A directory laid out as an OCI image layout, with content addressed blobs under `blobs/sha256`.
+pub struct OciLayout {
+    root: PathBuf,
+}
+
+impl OciLayout {
+    /// Opens the layout at root, creating it if it isn't there yet.
+    pub fn create(root: impl AsRef<Path>) -> Result<OciLayout, Error> {
+        let root = root.as_ref().to_path_buf();
+        std::fs::create_dir_all(root.join("blobs").join("sha256"))
+            .with_context(|| format!("Creating oci image layout at {:?}", root))?;
+        std::fs::write(
+            root.join(OCI_LAYOUT_FILE),
+            "{\"imageLayoutVersion\":\"1.0.0\"}",
+        )?;
+        Ok(OciLayout { root })
+    }
+
+    /// Opens an existing layout at root, e.g. one written by the puller.
+    pub fn open(root: impl AsRef<Path>) -> Result<OciLayout, Error> {
+        let root = root.as_ref().to_path_buf();
+        if !root.join(OCI_LAYOUT_FILE).exists() {
+            bail!(
+                "{:?} is not an oci image layout, it has no {} file",
+                root,
+                OCI_LAYOUT_FILE
+            );
+        }
+        Ok(OciLayout { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf, Error> {
+        Ok(self.root.join(blob_relative_path(digest)?))
+    }
+
+    /// If the layout already holds the blob with the right content.
+    pub async fn has_blob(&self, digest: &str) -> Result<bool, Error> {
+        let path = self.blob_path(digest)?;
+        if !path.exists() {
+            return Ok(false);
+        }
+        let (sha, _) = Sha256Value::from_path(&path).await?;
+        Ok(format!("sha256:{}", sha) == digest)
+    }
+
+    /// Temp file next to the blobs, for downloading into before moving it to the blob path.
+    pub fn temp_blob_file(&self) -> Result<tempfile::NamedTempFile, Error> {
+        Ok(tempfile::NamedTempFile::new_in(
+            self.root.join("blobs").join("sha256"),
+        )?)
+    }
+
+    pub fn write_blob(&self, digest: &str, content: &[u8]) -> Result<PathBuf, Error> {
+        let sha = Sha256Value::try_from(content)?;
+        if format!("sha256:{}", sha) != digest {
+            bail!(
+                "Refusing to write blob {}, its content hashes to sha256:{}",
+                digest,
+                sha
+            );
+        }
+        let path = self.blob_path(digest)?;
+        atomic_file::write(&path, content)?;
+        Ok(path)
+    }
+
+    pub fn read_index(&self) -> Result<ImageIndex, Error> {
+        let index_path = self.root.join(INDEX_FILE);
+        if !index_path.exists() {
+            return Ok(ImageIndex::default());
+        }
+        let content = std::fs::read_to_string(&index_path)?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Attempting to parse image index {:?}", index_path))
+    }
+
+    /// Adds the manifest to the index, replacing any entry for the same digest or reference name.
+    pub fn add_to_index(&self, descriptor: Descriptor) -> Result<(), Error> {
+        let ref_name = |d: &Descriptor| {
+            d.annotations
+                .as_ref()
+                .and_then(|a| a.get(REF_NAME_ANNOTATION))
+                .cloned()
+        };
+        let mut index = self.read_index()?;
+        index.manifests.retain(|d| {
+            d.digest != descriptor.digest
+                && (ref_name(d).is_none() || ref_name(d) != ref_name(&descriptor))
+        });
+        index.manifests.push(descriptor);
+
+        atomic_file::write(
+            &self.root.join(INDEX_FILE),
+            serde_json::to_vec_pretty(&index)?,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_layout() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        assert!(OciLayout::open(dir.path()).is_err());
+        let layout = OciLayout::create(dir.path())?;
+        assert!(dir.path().join(OCI_LAYOUT_FILE).exists());
+        assert!(OciLayout::open(dir.path()).is_ok());
+
+        let content = b"{}";
+        let digest = format!("sha256:{}", Sha256Value::try_from(&content[..])?);
+        assert!(!layout.has_blob(&digest).await?);
+        layout.write_blob(&digest, content)?;
+        assert!(layout.has_blob(&digest).await?);
+        assert!(layout.write_blob(&digest, b"{ }").is_err());
+        assert!(layout.blob_path("sha256:../../etc/passwd").is_err());
+
+        let mut annotations = BTreeMap::default();
+        annotations.insert(REF_NAME_ANNOTATION.to_string(), "latest".to_string());
+        let descriptor = Descriptor {
+            media_type: "application/vnd.oci.image.manifest.v1+json".to_string(),
+            digest: digest.clone(),
+            size: 2,
+            annotations: Some(annotations),
+        };
+        layout.add_to_index(descriptor.clone())?;
+        layout.add_to_index(descriptor.clone())?;
+        assert_eq!(layout.read_index()?.manifests, vec![descriptor]);
+        Ok(())
+    }
+}
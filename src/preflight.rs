@@ -0,0 +1,164 @@
+use std::{collections::HashSet, path::Path};
+
+use anyhow::Error;
+
+use crate::{container_specs::Manifest, hash::sha256_value::Sha256Value, UploadMetadata};
+
+/// Checks that everything a push needs from the local outputs is there and unchanged since the merge,
+/// before we go talk to any registry. Returns a description of every problem found.
+pub async fn check_push_inputs(
+    manifest: &Manifest,
+    config_path: &Path,
+    upload_metadata: &UploadMetadata,
+) -> Result<Vec<String>, Error> {
+    let mut problems = Vec::default();
+
+    let mut local_digests = HashSet::new();
+    for layer_config in upload_metadata.layer_configs.iter() {
+        local_digests.insert(layer_config.outer_sha256.as_str());
+        match std::fs::metadata(&layer_config.layer_data) {
+            Ok(metadata) if metadata.len() == layer_config.compressed_length => (),
+            Ok(metadata) => problems.push(format!(
+                "Local layer {} ({}) is {} bytes, expected {}",
+                layer_config.layer_data,
+                layer_config.outer_sha256,
+                metadata.len(),
+                layer_config.compressed_length
+            )),
+            Err(e) => problems.push(format!(
+                "Local layer {} ({}) is not readable: {}",
+                layer_config.layer_data, layer_config.outer_sha256, e
+            )),
+        }
+    }
+
+    let remote_metadata = upload_metadata.remote_metadata.as_ref();
+    // The base layers of an oci image layout are among the local ones, the source repository isn't used.
+    let has_oci_layout = remote_metadata
+        .map(|r| r.oci_layout.is_some())
+        .unwrap_or(false);
+    let has_source_repository = remote_metadata
+        .map(|r| r.registry.is_some() && r.repository.is_some())
+        .unwrap_or(false);
+    // If the base image manifest is around we can tell exactly which layers the source repository has.
+    let mut source_digests: Option<HashSet<String>> = None;
+    if let Some(base_manifest) = remote_metadata.and_then(|r| r.manifest.as_ref()) {
+        match Manifest::parse_file(&base_manifest.path) {
+            Ok(m) => source_digests = Some(m.layers.into_iter().map(|l| l.digest).collect()),
+            Err(e) => problems.push(format!(
+                "Base image manifest {} is not readable: {:#}",
+                base_manifest.path, e
+            )),
+        }
+    }
+
+    for layer in manifest.layers.iter() {
+        if local_digests.contains(layer.digest.as_str()) {
+            continue;
+        }
+        if has_oci_layout {
+            problems.push(format!(
+                "Layer {} is neither in the local outputs nor among the base layers of the oci image layout",
+                layer.digest
+            ));
+        } else if !has_source_repository {
+            problems.push(format!(
+                "Layer {} is not in the local outputs, and there is no source repository to fetch it from",
+                layer.digest
+            ));
+        } else if let Some(source_digests) = &source_digests {
+            if !source_digests.contains(&layer.digest) {
+                problems.push(format!(
+                    "Layer {} is neither in the local outputs nor in the source image manifest",
+                    layer.digest
+                ));
+            }
+        }
+    }
+
+    match Sha256Value::from_path(config_path).await {
+        Ok((config_sha, config_len)) => {
+            let config_digest = format!("sha256:{}", config_sha);
+            if config_digest != manifest.config.digest
+                || config_len.0 as u64 != manifest.config.size
+            {
+                problems.push(format!(
+                    "Config {:?} is {} / {} bytes, but the manifest expects {} / {} bytes",
+                    config_path,
+                    config_digest,
+                    config_len.0,
+                    manifest.config.digest,
+                    manifest.config.size
+                ));
+            }
+        }
+        Err(e) => problems.push(format!("Config {:?} is not readable: {}", config_path, e)),
+    }
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        container_specs::blob_reference::BlobReferenceType, hash::sha256_value::DataLen,
+        merge_config::RemoteMetadata, test_util::TestDir,
+    };
+
+    #[tokio::test]
+    async fn test_check_push_inputs() -> Result<(), Error> {
+        let dir = TestDir::new()?;
+
+        let config_path = dir.path().join("config.json");
+        std::fs::write(&config_path, "{}")?;
+        let (config_sha, config_len) = Sha256Value::from_path(&config_path).await?;
+
+        let (layer_config, layer_sha, layer_len) = dir.write_layer("layer.tgz", "layer").await?;
+
+        let mut manifest = Manifest::default();
+        manifest.update_config(config_sha, config_len);
+        manifest.add_layer(layer_sha, layer_len, BlobReferenceType::LayerGz);
+
+        let mut upload_metadata = UploadMetadata {
+            layer_configs: vec![layer_config],
+            ..UploadMetadata::default()
+        };
+        assert!(check_push_inputs(&manifest, &config_path, &upload_metadata)
+            .await?
+            .is_empty());
+
+        // A base layer we don't have locally, with no source repository to get it from.
+        let base_sha = Sha256Value::try_from(&b"base"[..])?;
+        manifest.add_layer(base_sha, DataLen(4), BlobReferenceType::LayerGz);
+        upload_metadata.layer_configs[0].compressed_length += 1;
+        std::fs::write(&config_path, "{ }")?;
+
+        let problems = check_push_inputs(&manifest, &config_path, &upload_metadata).await?;
+        assert_eq!(problems.len(), 3, "{:#?}", problems);
+
+        upload_metadata.remote_metadata = Some(RemoteMetadata {
+            registry: Some("l.gcr.io".to_string()),
+            repository: Some("google/bazel".to_string()),
+            ..RemoteMetadata::default()
+        });
+        let problems = check_push_inputs(&manifest, &config_path, &upload_metadata).await?;
+        assert_eq!(problems.len(), 2, "{:#?}", problems);
+
+        // A base manifest that can't be read is a problem of its own, not a reason to skip the check.
+        let remote_metadata = upload_metadata.remote_metadata.as_mut().unwrap();
+        remote_metadata.manifest = Some(dir.path_pair("missing.json"));
+        let problems = check_push_inputs(&manifest, &config_path, &upload_metadata).await?;
+        assert_eq!(problems.len(), 3, "{:#?}", problems);
+
+        // With an oci image layout the base layers have to be local, whatever registry is named.
+        upload_metadata.remote_metadata = Some(RemoteMetadata {
+            oci_layout: Some(dir.path_pair("layout")),
+            ..RemoteMetadata::default()
+        });
+        let problems = check_push_inputs(&manifest, &config_path, &upload_metadata).await?;
+        assert_eq!(problems.len(), 3, "{:#?}", problems);
+        assert!(problems[1].contains("oci image layout"), "{:#?}", problems);
+        Ok(())
+    }
+}
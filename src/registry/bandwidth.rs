@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Error};
+use tokio::sync::Mutex;
+
+use super::ops::{BYTES_IN_GB, BYTES_IN_MB};
+
+/// Paces the bytes passing through it to a maximum rate, shared by every transfer it is handed to.
+pub struct BandwidthLimiter {
+    bytes_per_second: u64,
+    // When the bytes handed out so far have been paid for.
+    next_free: Mutex<Instant>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(bytes_per_second: u64) -> BandwidthLimiter {
+        BandwidthLimiter {
+            bytes_per_second,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until sending `bytes` more keeps us within the limit.
+    pub async fn consume(&self, bytes: usize) {
+        let start = {
+            let mut next_free = self.next_free.lock().await;
+            let start = (*next_free).max(Instant::now());
+            *next_free =
+                start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+            start
+        };
+        tokio::time::sleep_until(start.into()).await;
+    }
+}
+
+/// Parses a rate in bytes per second, optionally with a K, M or G suffix, e.g. `512K` or `10M`.
+pub fn parse_bytes_per_second(s: &str) -> Result<u64, Error> {
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1024),
+        Some('M') => (&s[..s.len() - 1], BYTES_IN_MB),
+        Some('G') => (&s[..s.len() - 1], BYTES_IN_GB),
+        _ => (s, 1),
+    };
+    let bytes_per_second = number
+        .parse::<u64>()
+        .with_context(|| format!("Invalid bandwidth limit {:?}", s))?
+        .checked_mul(multiplier)
+        .with_context(|| format!("Bandwidth limit {:?} is too large", s))?;
+    if bytes_per_second == 0 {
+        bail!("Bandwidth limit {:?} would never transfer anything", s)
+    }
+    Ok(bytes_per_second)
+}
+
+/// The global bandwidth limit, along with the limits of individual registries.
+/// Every repository on a registry shares the limiter of that registry.
+#[derive(Default)]
+pub struct BandwidthLimits {
+    global: Option<Arc<BandwidthLimiter>>,
+    per_registry: HashMap<String, Arc<BandwidthLimiter>>,
+}
+
+impl BandwidthLimits {
+    /// `per_registry` is a comma separated list in registry:rate format, e.g. foo.gcr.io:10M,bar.gcr.io:512K
+    pub fn new(global: Option<&str>, per_registry: Option<&str>) -> Result<BandwidthLimits, Error> {
+        let global = global
+            .map(|g| parse_bytes_per_second(g).map(|r| Arc::new(BandwidthLimiter::new(r))))
+            .transpose()?;
+
+        let mut limits = HashMap::default();
+        for e in per_registry
+            .unwrap_or_default()
+            .split(',')
+            .filter(|e| !e.is_empty())
+        {
+            let (registry, rate) = e
+                .rsplit_once(':')
+                .with_context(|| format!("Failed to parse registry bandwidth limit from {}", e))?;
+            // Keyed by host, so a registry given as a url finds the limit all the same.
+            let host = super::http::registry_host(registry).with_context(|| {
+                format!("Invalid registry {:?} in bandwidth limit {}", registry, e)
+            })?;
+            limits.insert(
+                host,
+                Arc::new(BandwidthLimiter::new(parse_bytes_per_second(rate)?)),
+            );
+        }
+
+        Ok(BandwidthLimits {
+            global,
+            per_registry: limits,
+        })
+    }
+
+    /// Limiters a transfer to the registry with the given host has to pass through.
+    pub fn limiters_for(&self, registry_host: &str) -> Vec<Arc<BandwidthLimiter>> {
+        self.global
+            .iter()
+            .chain(self.per_registry.get(registry_host))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_limits() -> Result<(), Error> {
+        assert_eq!(parse_bytes_per_second("1000")?, 1000);
+        assert_eq!(parse_bytes_per_second("512k")?, 512 * 1024);
+        assert_eq!(parse_bytes_per_second("10M")?, 10 * BYTES_IN_MB);
+        assert!(parse_bytes_per_second("0").is_err());
+        assert!(parse_bytes_per_second("fast").is_err());
+        assert!(parse_bytes_per_second("99999999999G").is_err());
+
+        let limits = BandwidthLimits::new(
+            Some("1G"),
+            Some("localhost:5000:1M,https://gcr.io:2M,http://insecure.io:80:3M"),
+        )?;
+        assert_eq!(limits.limiters_for("localhost:5000").len(), 2);
+        assert_eq!(
+            limits.limiters_for("gcr.io")[1].bytes_per_second,
+            2 * BYTES_IN_MB
+        );
+        assert_eq!(limits.limiters_for("insecure.io").len(), 2);
+        assert_eq!(limits.limiters_for("docker.io").len(), 1);
+        assert!(BandwidthLimits::default().limiters_for("gcr.io").is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_consume_paces() {
+        let limiter = BandwidthLimiter::new(100_000);
+        let start = Instant::now();
+        for _ in 0..4 {
+            limiter.consume(5_000).await;
+        }
+        // The first chunk goes right away, the following three each wait for the one before to be paid for.
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Error};
+
+use super::RegistryName;
+use crate::atomic_file;
+
+// registry host/repository -> digest -> unix seconds when we last saw it present
+type Entries = BTreeMap<String, BTreeMap<String, u64>>;
+
+/// On disk record of the blobs we know to be present in registry repositories,
+/// so we don't have to ask the registry about them on every push.
+/// Entries older than the ttl are ignored, since registries can garbage collect blobs.
+pub struct PresentBlobIndex {
+    path: PathBuf,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn read_entries(path: &Path) -> Result<Entries, Error> {
+    if !path.exists() {
+        return Ok(Entries::default());
+    }
+    let content = std::fs::read_to_string(path)?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Attempting to parse present blob index {:?}", path))
+}
+
+impl PresentBlobIndex {
+    pub fn load(path: impl AsRef<Path>, ttl: Duration) -> Result<PresentBlobIndex, Error> {
+        let path = path.as_ref().to_path_buf();
+        let entries = match read_entries(&path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!(
+                    "Warning, ignoring unreadable present blob index {:?}: {:#}",
+                    path, e
+                );
+                Entries::default()
+            }
+        };
+        Ok(PresentBlobIndex {
+            path,
+            ttl,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn is_present(&self, registry_name: &RegistryName, digest: &str) -> bool {
+        let oldest_valid = now_secs().saturating_sub(self.ttl.as_secs());
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&registry_name.to_string())
+            .and_then(|digests| digests.get(digest))
+            .map(|seen| *seen >= oldest_valid)
+            .unwrap_or(false)
+    }
+
+    pub fn record_present(&self, registry_name: &RegistryName, digest: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        entries
+            .entry(registry_name.to_string())
+            .or_default()
+            .insert(digest.to_string(), now_secs());
+    }
+
+    /// Writes the index back to disk, merging with whatever other pushes wrote in the mean time.
+    pub fn save(&self) -> Result<(), Error> {
+        let oldest_valid = now_secs().saturating_sub(self.ttl.as_secs());
+        let mut merged = read_entries(&self.path).unwrap_or_default();
+        {
+            let entries = self.entries.lock().unwrap();
+            for (registry, digests) in entries.iter() {
+                let merged_digests = merged.entry(registry.clone()).or_default();
+                for (digest, seen) in digests.iter() {
+                    let merged_seen = merged_digests.entry(digest.clone()).or_default();
+                    *merged_seen = (*merged_seen).max(*seen);
+                }
+            }
+        }
+        for digests in merged.values_mut() {
+            digests.retain(|_, seen| *seen >= oldest_valid);
+        }
+        merged.retain(|_, digests| !digests.is_empty());
+
+        atomic_file::write(&self.path, serde_json::to_vec(&merged)?)
+            .with_context(|| format!("Writing present blob index to {:?}", self.path))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_ttl() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("present_blobs.json");
+        let registry_name = RegistryName {
+            host: "gcr.io".to_string(),
+            repository: "team/app".to_string(),
+        };
+        let other_registry_name = RegistryName {
+            host: "gcr.io".to_string(),
+            repository: "mirror/team-app".to_string(),
+        };
+
+        let index = PresentBlobIndex::load(&path, Duration::from_secs(3600))?;
+        assert!(!index.is_present(&registry_name, "sha256:0"));
+        index.record_present(&registry_name, "sha256:0");
+        assert!(index.is_present(&registry_name, "sha256:0"));
+        assert!(!index.is_present(&other_registry_name, "sha256:0"));
+        index.save()?;
+
+        let reloaded = PresentBlobIndex::load(&path, Duration::from_secs(3600))?;
+        assert!(reloaded.is_present(&registry_name, "sha256:0"));
+
+        let mut stale = Entries::default();
+        stale
+            .entry(registry_name.to_string())
+            .or_default()
+            .insert("sha256:1".to_string(), now_secs() - 7200);
+        std::fs::write(&path, serde_json::to_string(&stale)?)?;
+        let expired = PresentBlobIndex::load(&path, Duration::from_secs(3600))?;
+        assert!(!expired.is_present(&registry_name, "sha256:1"));
+        Ok(())
+    }
+}
@@ -1,7 +1,10 @@
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
 
 use crate::hash::sha256_value::Sha256Value;
+use crate::registry::bandwidth::BandwidthLimiter;
+use crate::registry::metrics::BlobOperationKind;
 use crate::registry::ops::BYTES_IN_MB;
 use crate::registry::BlobStore;
 use anyhow::{bail, Context, Error};
@@ -43,6 +46,39 @@ impl BlobStore for super::HttpRegistry {
         }
     }
 
+    async fn blob_size(&self, digest: &str) -> Result<Option<u64>, Error> {
+        let uri = self.repository_uri_from_path(format!("/blobs/{}", digest))?;
+
+        let mut r = self
+            .http_client
+            .request_simple(&uri, http::Method::HEAD, 3)
+            .await
+            .context("fetching blob size")?;
+
+        if r.status() == StatusCode::NOT_FOUND {
+            Ok(None)
+        } else if r.status() == StatusCode::OK {
+            let content_length = r
+                .headers()
+                .get(http::header::CONTENT_LENGTH)
+                .with_context(|| format!("Missing content length for blob at {:#?}", uri))?;
+            let size = content_length.to_str()?.parse::<u64>().with_context(|| {
+                format!(
+                    "Invalid content length {:?} for blob at {:#?}",
+                    content_length, uri
+                )
+            })?;
+            Ok(Some(size))
+        } else {
+            bail!(
+                "Failed call for blob size {:#?} -- {:#?}, body: {:#?}",
+                uri,
+                r.status().as_str(),
+                dump_body_to_string(&mut r).await?
+            )
+        }
+    }
+
     async fn download_blob(
         &self,
         target_file: &Path,
@@ -51,6 +87,7 @@ impl BlobStore for super::HttpRegistry {
         progress_bar: Option<ProgressBar>,
     ) -> Result<(), Error> {
         let target_file = target_file.to_path_buf();
+        let start = Instant::now();
 
         let uri = self.repository_uri_from_path(format!("/blobs/{}", digest))?;
         let mut response = self
@@ -83,6 +120,9 @@ impl BlobStore for super::HttpRegistry {
         while let Some(chunk) = body.next().await {
             let data = chunk?;
             total_bytes += data.len();
+            for bandwidth_limiter in self.bandwidth_limiters.iter() {
+                bandwidth_limiter.consume(data.len()).await;
+            }
 
             if let Some(progress_bar) = &progress_bar {
                 progress_bar.set_position(total_bytes as u64 / BYTES_IN_MB);
@@ -110,6 +150,12 @@ impl BlobStore for super::HttpRegistry {
                 total_bytes
             )
         }
+        self.http_client.metrics.record_blob_operation(
+            digest,
+            BlobOperationKind::Download,
+            total_bytes as u64,
+            start.elapsed(),
+        );
         Ok(())
     }
 
@@ -120,6 +166,7 @@ impl BlobStore for super::HttpRegistry {
         length: u64,
         progress_bar: Option<ProgressBar>,
     ) -> Result<(), Error> {
+        let start = Instant::now();
         let post_target_uri = self.repository_uri_from_path("/blobs/uploads/")?;
         // We expect our POST request to get a location header of where to perform the real upload to.
 
@@ -142,10 +189,9 @@ impl BlobStore for super::HttpRegistry {
             )
         }
 
-        let location_uri = if let Some(location_header) = r.headers().get(http::header::LOCATION) {
+        let session_uri = if let Some(location_header) = r.headers().get(http::header::LOCATION) {
             let location_str = location_header.to_str()?;
-            let chr = if location_str.contains('?') { '&' } else { '?' };
-            format!("{}{}digest={}", location_str, chr, digest).parse::<Uri>().with_context(|| format!("Unable to parse location header response when doing post for new upload, location header was {:?}", location_str))?
+            location_str.parse::<Uri>().with_context(|| format!("Unable to parse location header response when doing post for new upload, location header was {:?}", location_str))?
         } else {
             let body = dump_body_to_string(&mut r).await?;
             bail!("Was a redirection response code, but missing Location header, invalid response from server, body:\n{:#?}", body);
@@ -153,56 +199,205 @@ impl BlobStore for super::HttpRegistry {
 
         // Sometimes we can receive new URI's that don't contain hosts
         // we need to supply this information from the last URI we used in that case
-        let location_uri = if location_uri.host().is_some() {
-            location_uri
+        let session_uri = if session_uri.host().is_some() {
+            session_uri
         } else {
             let mut parts = post_target_uri.into_parts();
-            parts.path_and_query = location_uri.path_and_query().cloned();
+            parts.path_and_query = session_uri.path_and_query().cloned();
             Uri::from_parts(parts).with_context(|| {
                 format!(
                     "Constructed an invalid uri from parts, new uri: {:?}",
-                    location_uri
+                    session_uri
                 )
             })?
         };
 
-        let total_uploaded_bytes = Arc::new(Mutex::new(0));
+        // Until the upload completes the session stays registered, so if we get aborted it can still be cancelled.
+        self.open_upload_sessions
+            .lock()
+            .unwrap()
+            .insert(session_uri.clone());
+        let result = self
+            .upload_blob_to_session(&session_uri, local_path, digest, length, progress_bar)
+            .await;
+        self.open_upload_sessions
+            .lock()
+            .unwrap()
+            .remove(&session_uri);
+        if result.is_ok() {
+            self.http_client.metrics.record_blob_operation(
+                digest,
+                BlobOperationKind::Upload,
+                length,
+                start.elapsed(),
+            );
+        } else if let Err(e) = self.delete_upload_session(&session_uri).await {
+            tracing::debug!(
+                "Failed to cancel upload session {:#?} after a failed upload: {:#?}",
+                session_uri,
+                e
+            );
+        }
+        result
+    }
+
+    async fn cancel_open_uploads(&self) -> Result<usize, Error> {
+        let session_uris: Vec<Uri> = self.open_upload_sessions.lock().unwrap().drain().collect();
+
+        let mut errors = Vec::default();
+        for session_uri in session_uris.iter() {
+            if let Err(e) = self.delete_upload_session(session_uri).await {
+                errors.push(format!("{}: {:#}", session_uri, e));
+            }
+        }
+        if !errors.is_empty() {
+            bail!(
+                "Failed to cancel {} upload sessions:\n{}",
+                errors.len(),
+                errors.join("\n")
+            );
+        }
+        Ok(session_uris.len())
+    }
+}
+
+impl super::HttpRegistry {
+    async fn delete_upload_session(&self, session_uri: &Uri) -> Result<(), Error> {
+        let mut r = self
+            .http_client
+            .request_simple(session_uri, http::Method::DELETE, 1)
+            .await
+            .context("Cancelling upload session")?;
+
+        // Not found means the registry already dropped the session.
+        if r.status() != StatusCode::NO_CONTENT
+            && r.status() != StatusCode::OK
+            && r.status() != StatusCode::ACCEPTED
+            && r.status() != StatusCode::NOT_FOUND
+        {
+            bail!(
+                "Failed call to cancel upload session {:#?} -- {:#?}, body: {:#?}",
+                session_uri,
+                r.status().as_str(),
+                dump_body_to_string(&mut r).await?
+            )
+        }
+        Ok(())
+    }
+
+    async fn upload_blob_to_session(
+        &self,
+        session_uri: &Uri,
+        local_path: &Path,
+        digest: &str,
+        length: u64,
+        progress_bar: Option<ProgressBar>,
+    ) -> Result<(), Error> {
+        let chr = if session_uri.query().is_some() {
+            '&'
+        } else {
+            '?'
+        };
+        let location_uri = format!("{}{}digest={}", session_uri, chr, digest)
+            .parse::<Uri>()
+            .with_context(|| {
+                format!(
+                    "Unable to construct the upload uri for session {:?}",
+                    session_uri
+                )
+            })?;
 
         struct Context {
             progress_bar: Option<ProgressBar>,
             length: u64,
+            digest: String,
             local_path: std::path::PathBuf,
+            total_uploaded_bytes: Mutex<usize>,
+            local_file_changed: Mutex<Option<String>>,
+            bandwidth_limiters: Vec<Arc<BandwidthLimiter>>,
         }
-        let mut r = self
+        let upload_context = Arc::new(Context {
+            progress_bar,
+            length,
+            digest: digest.to_string(),
+            local_path: local_path.to_path_buf(),
+            total_uploaded_bytes: Mutex::new(0),
+            local_file_changed: Mutex::new(None),
+            bandwidth_limiters: self.bandwidth_limiters.clone(),
+        });
+        let response = self
             .http_client
             .request(
                 &location_uri,
-                Arc::new(Context {
-                    progress_bar,
-                    length,
-                    local_path: local_path.to_path_buf(),
-                }),
+                Arc::clone(&upload_context),
                 |context, builder| async move {
                     let f = tokio::fs::File::open(context.local_path.clone()).await?;
+                    let length = context.length;
 
+                    // We hash what we send as we go, so if the file changed since the merge computed its digest
+                    // we abort the upload with a clear error rather than have the registry reject it after the full transfer.
                     let stream = futures::stream::unfold(
-                        (context.progress_bar.clone(), ReaderStream::new(f), 0),
-                        |(progress_bar_cp, mut reader_stream, read_bytes)| async move {
-                            let nxt_chunk = reader_stream.next().await?;
-
-                            match nxt_chunk {
-                                Ok(chunk) => {
+                        (context, ReaderStream::new(f), 0, sha2::Sha256::new(), false),
+                        |(context, mut reader_stream, read_bytes, mut hasher, done)| async move {
+                            if done {
+                                return None;
+                            }
+                            let failure = match reader_stream.next().await {
+                                Some(Ok(chunk)) => {
                                     let read_bytes: usize = read_bytes + chunk.len();
-                                    if let Some(progress_bar) = &progress_bar_cp {
+                                    *context.total_uploaded_bytes.lock().await = read_bytes;
+                                    if let Some(progress_bar) = &context.progress_bar {
                                         progress_bar.set_position(read_bytes as u64 / BYTES_IN_MB);
                                     }
-                                    Some((Ok(chunk), (progress_bar_cp, reader_stream, read_bytes)))
+                                    if read_bytes as u64 <= context.length {
+                                        hasher.update(&chunk[..]);
+                                        for bandwidth_limiter in context.bandwidth_limiters.iter() {
+                                            bandwidth_limiter.consume(chunk.len()).await;
+                                        }
+                                        return Some((
+                                            Ok(chunk),
+                                            (context, reader_stream, read_bytes, hasher, false),
+                                        ));
+                                    }
+                                    format!(
+                                        "read at least {} bytes, expected {}",
+                                        read_bytes, context.length
+                                    )
                                 }
-                                Err(ex) => {
+                                Some(Err(ex)) => {
                                     let e: Box<dyn std::error::Error + Send + Sync> = Box::new(ex);
-                                    Some((Err(e), (progress_bar_cp, reader_stream, read_bytes)))
+                                    return Some((
+                                        Err(e),
+                                        (context, reader_stream, read_bytes, hasher, true),
+                                    ));
                                 }
-                            }
+                                None => {
+                                    let sha_str =
+                                        Sha256Value::new_from_slice(&hasher.clone().finalize())
+                                            .map(|s| format!("sha256:{}", s))
+                                            .unwrap_or_default();
+                                    if read_bytes as u64 != context.length {
+                                        format!(
+                                            "read {} bytes, expected {}",
+                                            read_bytes, context.length
+                                        )
+                                    } else if sha_str != context.digest {
+                                        format!(
+                                            "content hashes to {}, expected {}",
+                                            sha_str, context.digest
+                                        )
+                                    } else {
+                                        return None;
+                                    }
+                                }
+                            };
+                            let message = format!(
+                                "Local file changed since merge, aborted uploading {:?}: {}",
+                                context.local_path, failure
+                            );
+                            *context.local_file_changed.lock().await = Some(message.clone());
+                            let e: Box<dyn std::error::Error + Send + Sync> = message.into();
+                            Some((Err(e), (context, reader_stream, read_bytes, hasher, true)))
                         },
                     );
 
@@ -214,20 +409,21 @@ impl BlobStore for super::HttpRegistry {
 
                     builder
                         .method(http::Method::PUT)
-                        .header("Content-Length", context.length)
+                        .header("Content-Length", length)
                         .header("Content-Type", "application/octet-stream")
                         .body(body)
                         .map_err(|e| e.into())
                 },
                 3,
             )
-            .await
-            .context("Performing upload bytes operation")?;
+            .await;
 
-        let total_uploaded_bytes: usize = {
-            let m = total_uploaded_bytes.lock().await;
-            *m
-        };
+        if let Some(message) = upload_context.local_file_changed.lock().await.take() {
+            bail!(message);
+        }
+        let mut r = response.context("Performing upload bytes operation")?;
+
+        let total_uploaded_bytes: usize = *upload_context.total_uploaded_bytes.lock().await;
         if r.status() != StatusCode::CREATED && r.status() != StatusCode::OK {
             bail!("Blob Upload: Expected to get status code OK, but got {:#?},\nUploading {:?}\nUploading to: {:#?}\nBody:\n{:#?}\nUploaded: {} bytes\nExpected length: {}", r.status(), local_path, &location_uri, dump_body_to_string(&mut r).await?, total_uploaded_bytes, length)
         }
@@ -1,12 +1,31 @@
+use std::time::Instant;
+
+use crate::registry::metrics::BlobOperationKind;
 use crate::registry::{CopyOperations, RegistryName};
 use anyhow::{bail, Context as _, Error};
 
-use http::StatusCode;
+use http::{StatusCode, Uri};
+
+pub(super) fn registry_name_for(registry_uri: &Uri, name: &str) -> RegistryName {
+    let host = registry_uri.host().unwrap_or_default();
+    let default_port = match registry_uri.scheme_str() {
+        Some("http") => 80,
+        _ => 443,
+    };
+    let host = match registry_uri.port_u16() {
+        Some(port) if port != default_port => format!("{}:{}", host, port),
+        _ => host.to_string(),
+    };
+    RegistryName {
+        host,
+        repository: name.to_string(),
+    }
+}
 
 #[async_trait::async_trait]
 impl CopyOperations for super::HttpRegistry {
     fn registry_name(&self) -> RegistryName {
-        RegistryName(self.name.clone())
+        registry_name_for(&self.registry_uri, &self.name)
     }
 
     async fn try_copy_from(
@@ -14,11 +33,21 @@ impl CopyOperations for super::HttpRegistry {
         source_registry_name: &RegistryName,
         digest: &str,
     ) -> Result<(), Error> {
+        let registry_name = self.registry_name();
+        if !registry_name.same_host(source_registry_name) {
+            bail!(
+                "Unable to mount {} from {} into {}, they are on different registry hosts",
+                digest,
+                source_registry_name,
+                registry_name
+            )
+        }
         let uri = self.repository_uri_from_path(format!(
             "/blobs/uploads/?mount={}&from={}",
-            digest, source_registry_name
+            digest, source_registry_name.repository
         ))?;
 
+        let start = Instant::now();
         let r = self
             .http_client
             .request(
@@ -35,6 +64,13 @@ impl CopyOperations for super::HttpRegistry {
             .context("Issusing http request for copy between registries")?;
 
         if r.status() == StatusCode::CREATED {
+            // The bytes never pass through us on a mount.
+            self.http_client.metrics.record_blob_operation(
+                digest,
+                BlobOperationKind::Mount,
+                0,
+                start.elapsed(),
+            );
             Ok(())
         } else {
             bail!("Failed to request {:#?} -- {:#?}", uri, r.status().as_str())
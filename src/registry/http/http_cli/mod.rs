@@ -1,6 +1,7 @@
 mod authentication_flow;
 mod private_impl;
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use anyhow::Context;
@@ -12,6 +13,7 @@ use hyper::{Body, Client};
 use tokio::sync::Mutex;
 use std::cmp::max;
 
+use crate::registry::metrics::MetricsRecorder;
 use crate::registry::DockerAuthenticationHelper;
 
 use self::authentication_flow::AuthResponse;
@@ -24,6 +26,67 @@ pub struct HttpCli {
     pub auth_info: Arc<Mutex<Option<AuthResponse>>>,
     pub docker_authorization_helpers: Arc<Vec<DockerAuthenticationHelper>>,
     pub registry: String,
+    pub metrics: Arc<MetricsRecorder>,
+}
+
+/// What connections to the same registry host share: the connection pool, so TLS handshakes are reused,
+/// and the tokens handed out for each repository.
+pub struct HostClient {
+    inner_client: Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>,
+    // Tokens are usually scoped to a repository, sharing one between repositories would have them
+    // take turns overwriting it.
+    auth_infos: std::sync::Mutex<HashMap<String, Arc<Mutex<Option<AuthResponse>>>>>,
+}
+
+impl Default for HostClient {
+    fn default() -> Self {
+        HostClient::new()
+    }
+}
+
+impl HostClient {
+    pub fn new() -> HostClient {
+        use hyper_rustls::ConfigBuilderExt;
+
+        let tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_native_roots()
+            .with_no_client_auth();
+
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(tls)
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        HostClient {
+            inner_client: Client::builder().build::<_, hyper::Body>(https),
+            auth_infos: Default::default(),
+        }
+    }
+
+    pub fn http_cli(
+        &self,
+        registry: &str,
+        repository: &str,
+        docker_authorization_helpers: Arc<Vec<DockerAuthenticationHelper>>,
+        metrics: Arc<MetricsRecorder>,
+    ) -> HttpCli {
+        let auth_info = Arc::clone(
+            self.auth_infos
+                .lock()
+                .unwrap()
+                .entry(repository.to_string())
+                .or_default(),
+        );
+        HttpCli {
+            inner_client: self.inner_client.clone(),
+            auth_info,
+            docker_authorization_helpers,
+            registry: registry.to_string(),
+            metrics,
+        }
+    }
 }
 
 impl HttpCli {
@@ -59,6 +122,7 @@ impl HttpCli {
         let mut auth_attempt = 0;
         let auth_retries = max(retries, 3);
         let error = loop {
+            self.metrics.record_request();
             match run_single_request(
                 self.auth_info.clone(),
                 &uri,
@@ -104,11 +168,19 @@ impl HttpCli {
                             drop(ai);
                             continue;
                         }
-                        RequestFailType::ConnectError(_) => continue,
-                        RequestFailType::ServerError(_, _) => continue, // Retry server errors
+                        RequestFailType::ConnectError(_) => {
+                            self.metrics.record_retry();
+                            continue;
+                        }
+                        RequestFailType::ServerError(_, _) => {
+                            // Retry server errors
+                            self.metrics.record_retry();
+                            continue;
+                        }
                         RequestFailType::HyperError(_) => break err, // terminal.
                         RequestFailType::AnyhowError(_) => break err, // terminal.
                         RequestFailType::AuthFailure(_, auth_fail) => {
+                            self.metrics.record_auth_round_trip();
                             let auth_info = authentication_flow::authenticate_request(
                                 &auth_fail,
                                 &self.inner_client,
@@ -3,30 +3,43 @@ mod copy_operations;
 mod http_cli;
 mod util;
 use bytes::Bytes;
+pub use http_cli::HostClient;
 use http_cli::HttpCli;
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
 
 use crate::container_specs::manifest::Manifest;
+use crate::hash::sha256_value::Sha256Value;
 use crate::registry::http::http_cli::RequestFailType;
 use crate::registry::http::util::dump_body_to_string;
 
 use anyhow::{bail, Context, Error};
 use http::Uri;
 use http::{Response, StatusCode};
-use hyper::{Body, Client};
-use hyper_rustls::ConfigBuilderExt;
+use hyper::Body;
 
 use tokio::time::timeout;
 
-use self::util::request_path_in_repository_as_string;
+use self::util::{
+    request_path_in_repository_accepting, request_path_in_repository_as_string,
+    MANIFEST_AND_INDEX_MEDIA_TYPES,
+};
 
-use super::{ContentAndContentType, DockerAuthenticationHelper};
+use super::bandwidth::{BandwidthLimiter, BandwidthLimits};
+use super::metrics::Metrics;
+use super::{
+    ContentAndContentType, DockerAuthenticationHelper, NotFound, TagAlreadyExists, TagPolicy,
+};
 
 pub struct HttpRegistry {
     registry_uri: Uri,
     name: String,
     http_client: HttpCli,
+    // Every blob transfer passes through these, global ones and ones for this registry.
+    bandwidth_limiters: Vec<Arc<BandwidthLimiter>>,
+    // Upload sessions we opened and haven't completed yet.
+    open_upload_sessions: std::sync::Mutex<HashSet<Uri>>,
 }
 
 #[async_trait::async_trait]
@@ -45,91 +58,138 @@ impl super::RegistryCore for HttpRegistry {
         &self,
         manifest: &Manifest,
         tag: &str,
+        tag_policy: &TagPolicy,
     ) -> Result<Option<String>, Error> {
         let manifest_bytes = Bytes::from(manifest.to_bytes()?);
 
-        if let Ok(content_and_type) = self.fetch_manifest_as_string(tag).await {
+        // The tag may point at an image index, so those are accepted too. Only a tag the registry
+        // doesn't have is free to take, any other failure means we can't tell what we would overwrite.
+        let existing = self
+            .try_resolve_manifest(tag)
+            .await
+            .with_context(|| format!("Looking up what tag {} points at", tag))?;
+        if let Some((existing_digest, content_and_type)) = existing {
             if manifest_bytes == content_and_type.content.as_bytes() {
                 return Ok(None);
             }
+            if !tag_policy.allows_overwrite(tag) {
+                return Err(TagAlreadyExists {
+                    tag: tag.to_string(),
+                    existing_digest,
+                }
+                .into());
+            }
         }
 
-        let post_target_uri = self.repository_uri_from_path(format!("/manifests/{}", tag))?;
+        self.put_manifest(manifest_bytes, manifest.media_type(), tag)
+            .await
+            .map(Some)
+            .with_context(|| format!("Uploading manifest:\n{:#?}", manifest))
+    }
 
-        let response = self
-            .http_client
-            .request(
-                &post_target_uri,
-                (),
-                |_, builder| async {
-                    builder
-                        .method(http::Method::PUT)
-                        .header("Content-Type", manifest.media_type())
-                        .body(Body::from(manifest_bytes.clone()))
-                        .map_err(|e| e.into())
-                },
-                0,
+    async fn upload_manifest_content(
+        &self,
+        content: &ContentAndContentType,
+        reference: &str,
+    ) -> Result<String, Error> {
+        let content_type = content.content_type.as_ref().ok_or_else(|| {
+            anyhow::anyhow!(
+                "Unable to upload manifest content to {}, it has no content type",
+                reference
             )
-            .await;
-
-        eprintln!("Got response: {:?}", response);
-
-        let mut r: Response<Body> = response?;
-        eprintln!("Got response code: {:?}", r.status());
+        })?;
+        self.put_manifest(
+            Bytes::from(content.content.clone()),
+            content_type,
+            reference,
+        )
+        .await
+    }
 
-        if r.status() != StatusCode::CREATED {
-            bail!("Expected to get status code CREATED, but got {:#?}, hitting url: {:#?},\nUploading {:#?}\nResponse:{}", r.status(), post_target_uri, manifest, dump_body_to_string(&mut r).await? )
+    async fn resolve_manifest(
+        &self,
+        reference: &str,
+    ) -> Result<(String, ContentAndContentType), Error> {
+        let uri = self.repository_uri_from_path(format!("/manifests/{}", reference))?;
+        let (content, reported_digest) = request_path_in_repository_accepting(
+            &self.http_client,
+            &uri,
+            MANIFEST_AND_INDEX_MEDIA_TYPES,
+        )
+        .await?;
+        let digest = format!(
+            "sha256:{}",
+            Sha256Value::try_from(content.content.as_bytes())?
+        );
+        if let Some(reported_digest) = reported_digest {
+            if reported_digest != digest {
+                bail!(
+                    "Registry reported digest {} for {}, but the content it sent hashes to {}",
+                    reported_digest,
+                    uri,
+                    digest
+                )
+            }
         }
+        Ok((digest, content))
+    }
 
-        if let Some(location_header) = r.headers().get(http::header::LOCATION) {
-            let location_str = location_header.to_str()?;
-            Ok(Some(location_str.to_string()))
-        } else {
-            bail!("We got a positive response code: {:#?}, however we are missing the location header as is required in the spec", r.status())
+    async fn try_resolve_manifest(
+        &self,
+        reference: &str,
+    ) -> Result<Option<(String, ContentAndContentType)>, Error> {
+        match self.resolve_manifest(reference).await {
+            Ok(resolved) => Ok(Some(resolved)),
+            Err(e) if e.is::<NotFound>() => Ok(None),
+            Err(e) => Err(e),
         }
     }
 }
 
+fn registry_uri(registry_base: &str) -> Result<Uri, Error> {
+    let mut uri_parts = registry_base.parse::<Uri>()?.into_parts();
+    // default to using https
+    if uri_parts.scheme.is_none() {
+        uri_parts.scheme = Some("https".parse()?);
+    }
+    uri_parts.path_and_query = Some("/".try_into()?);
+    Ok(Uri::from_parts(uri_parts)?)
+}
+
+/// Host of a registry given either as `host[:port]` or as a url, the way registry names hold it:
+/// with the port only when it isn't the default one for the scheme.
+pub(crate) fn registry_host(registry_base: &str) -> Result<String, Error> {
+    Ok(copy_operations::registry_name_for(&registry_uri(registry_base)?, "").host)
+}
+
 impl HttpRegistry {
     pub(crate) async fn from_maybe_domain_and_name<S: AsRef<str> + Send, S2: AsRef<str> + Send>(
         registry_base: S,
         name: S2,
         docker_authorization_helpers: Arc<Vec<DockerAuthenticationHelper>>,
+        bandwidth_limits: Arc<BandwidthLimits>,
+        metrics: Arc<Metrics>,
+        host_client: &HostClient,
     ) -> Result<HttpRegistry, Error> {
         let no_auth_helpers = docker_authorization_helpers.is_empty();
-        let mut uri_parts = registry_base.as_ref().parse::<Uri>()?.into_parts();
-        // default to using https
-        if uri_parts.scheme.is_none() {
-            uri_parts.scheme = Some("https".parse()?);
-        }
-        uri_parts.path_and_query = Some("/".try_into()?);
-
-        let registry_uri = Uri::from_parts(uri_parts)?;
-
-        let tls = rustls::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_native_roots()
-            .with_no_client_auth();
-
-        let https = hyper_rustls::HttpsConnectorBuilder::new()
-            .with_tls_config(tls)
-            .https_or_http()
-            .enable_http1()
-            .build();
-
-        let http_client: Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>> =
-            Client::builder().build::<_, hyper::Body>(https);
+        let registry_uri = registry_uri(registry_base.as_ref())?;
+        let registry_host = copy_operations::registry_name_for(&registry_uri, "").host;
 
         eprintln!("Connecting to registry {:?}", registry_uri);
         let reg = HttpRegistry {
             registry_uri: registry_uri.clone(),
             name: name.as_ref().to_string(),
-            http_client: HttpCli {
-                inner_client: http_client,
+            http_client: host_client.http_cli(
+                registry_base.as_ref(),
+                name.as_ref(),
                 docker_authorization_helpers,
-                auth_info: Default::default(),
-                registry: registry_base.as_ref().to_string(),
-            },
+                metrics.recorder_for(&copy_operations::registry_name_for(
+                    &registry_uri,
+                    name.as_ref(),
+                )),
+            ),
+            bandwidth_limiters: bandwidth_limits.limiters_for(&registry_host),
+            open_upload_sessions: Default::default(),
         };
 
         let req_uri = reg.v2_from_path("/")?;
@@ -174,6 +234,47 @@ impl HttpRegistry {
         Ok(reg)
     }
 
+    async fn put_manifest(
+        &self,
+        manifest_bytes: Bytes,
+        content_type: &str,
+        reference: &str,
+    ) -> Result<String, Error> {
+        let post_target_uri = self.repository_uri_from_path(format!("/manifests/{}", reference))?;
+
+        let response = self
+            .http_client
+            .request(
+                &post_target_uri,
+                (),
+                |_, builder| async {
+                    builder
+                        .method(http::Method::PUT)
+                        .header("Content-Type", content_type)
+                        .body(Body::from(manifest_bytes.clone()))
+                        .map_err(|e| e.into())
+                },
+                0,
+            )
+            .await;
+
+        eprintln!("Got response: {:?}", response);
+
+        let mut r: Response<Body> = response?;
+        eprintln!("Got response code: {:?}", r.status());
+
+        if r.status() != StatusCode::CREATED {
+            bail!("Expected to get status code CREATED, but got {:#?}, hitting url: {:#?},\nResponse:{}", r.status(), post_target_uri, dump_body_to_string(&mut r).await? )
+        }
+
+        if let Some(location_header) = r.headers().get(http::header::LOCATION) {
+            let location_str = location_header.to_str()?;
+            Ok(location_str.to_string())
+        } else {
+            bail!("We got a positive response code: {:#?}, however we are missing the location header as is required in the spec", r.status())
+        }
+    }
+
     fn v2_from_path<S: AsRef<str>>(&self, path: S) -> Result<Uri, Error> {
         let mut uri_builder = self.registry_uri.clone().into_parts();
         let path_ext = path.as_ref();
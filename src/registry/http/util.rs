@@ -1,4 +1,4 @@
-use crate::registry::ContentAndContentType;
+use crate::registry::{ContentAndContentType, NotFound};
 use anyhow::{bail, Context as _, Error};
 use http::Uri;
 use http::{Response, StatusCode};
@@ -19,21 +19,43 @@ pub(super) async fn dump_body_to_string(response: &mut Response<Body>) -> Result
     Ok(metadata.to_string())
 }
 
+pub(super) const MANIFEST_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.docker.distribution.manifest.v2+json",
+    "application/vnd.oci.image.manifest.v1+json",
+];
+
+pub(super) const MANIFEST_AND_INDEX_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.docker.distribution.manifest.v2+json",
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.oci.image.index.v1+json",
+];
+
 pub(super) async fn request_path_in_repository_as_string(
     client: &HttpCli,
     uri: &Uri,
 ) -> Result<ContentAndContentType, Error> {
+    request_path_in_repository_accepting(client, uri, MANIFEST_MEDIA_TYPES)
+        .await
+        .map(|(content, _)| content)
+}
+
+// Also returns the digest the registry reports for the content, if any.
+pub(super) async fn request_path_in_repository_accepting(
+    client: &HttpCli,
+    uri: &Uri,
+    accept: &'static [&'static str],
+) -> Result<(ContentAndContentType, Option<String>), Error> {
     let mut r = client
         .request(
             &uri,
             (),
             |_, c| async {
-                c.method(http::Method::GET)
-                    .header(
-                        "Accept",
-                        "application/vnd.docker.distribution.manifest.v2+json",
-                    )
-                    .header("Accept", "application/vnd.oci.image.manifest.v1+json")
+                accept
+                    .iter()
+                    .fold(c.method(http::Method::GET), |c, media_type| {
+                        c.header("Accept", *media_type)
+                    })
                     .body(Body::from(""))
                     .map_err(|e| e.into())
             },
@@ -45,6 +67,12 @@ pub(super) async fn request_path_in_repository_as_string(
     let metadata = dump_body_to_string(&mut r).await?;
 
     let status = r.status();
+    if status == StatusCode::NOT_FOUND {
+        return Err(NotFound {
+            uri: uri.to_string(),
+        }
+        .into());
+    }
     if status != StatusCode::OK {
         bail!(
             "Request to {:#?} failed, code: {:?}; body content:\n{:#?}",
@@ -55,15 +83,26 @@ pub(super) async fn request_path_in_repository_as_string(
     }
 
     let content_string = metadata;
+    let content_digest = r
+        .headers()
+        .get("docker-content-digest")
+        .map(|d| d.to_str().map(|d| d.to_string()))
+        .transpose()?;
 
     match r.headers().get("content-type") {
-        Some(c) => Ok(ContentAndContentType {
-            content_type: Some(c.to_str()?.to_string()),
-            content: content_string,
-        }),
-        None => Ok(ContentAndContentType {
-            content_type: None,
-            content: content_string,
-        }),
+        Some(c) => Ok((
+            ContentAndContentType {
+                content_type: Some(c.to_str()?.to_string()),
+                content: content_string,
+            },
+            content_digest,
+        )),
+        None => Ok((
+            ContentAndContentType {
+                content_type: None,
+                content: content_string,
+            },
+            content_digest,
+        )),
     }
 }
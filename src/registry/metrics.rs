@@ -0,0 +1,320 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Error};
+use serde::Serialize;
+
+use super::RegistryName;
+use crate::atomic_file;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum BlobOperationKind {
+    Upload,
+    Download,
+    Mount,
+}
+
+impl BlobOperationKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BlobOperationKind::Upload => "upload",
+            BlobOperationKind::Download => "download",
+            BlobOperationKind::Mount => "mount",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct BlobOperationMetrics {
+    pub digest: String,
+    pub operation: BlobOperationKind,
+    pub bytes: u64,
+    pub seconds: f64,
+    pub bytes_per_second: f64,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct RegistryMetrics {
+    pub registry: String,
+    pub repository: String,
+    pub requests: u64,
+    pub retries: u64,
+    pub auth_round_trips: u64,
+    pub blob_operations: Vec<BlobOperationMetrics>,
+}
+
+/// Records what happens on the requests to one registry repository as it happens.
+#[derive(Default)]
+pub struct MetricsRecorder {
+    requests: AtomicU64,
+    retries: AtomicU64,
+    auth_round_trips: AtomicU64,
+    blob_operations: Mutex<Vec<BlobOperationMetrics>>,
+}
+
+impl MetricsRecorder {
+    pub fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_auth_round_trip(&self) {
+        self.auth_round_trips.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_blob_operation(
+        &self,
+        digest: &str,
+        operation: BlobOperationKind,
+        bytes: u64,
+        elapsed: Duration,
+    ) {
+        let seconds = elapsed.as_secs_f64();
+        let bytes_per_second = if seconds > 0.0 {
+            bytes as f64 / seconds
+        } else {
+            0.0
+        };
+        self.blob_operations
+            .lock()
+            .unwrap()
+            .push(BlobOperationMetrics {
+                digest: digest.to_string(),
+                operation,
+                bytes,
+                seconds,
+                bytes_per_second,
+            });
+    }
+
+    fn snapshot(&self, registry_name: &RegistryName) -> RegistryMetrics {
+        RegistryMetrics {
+            registry: registry_name.host.clone(),
+            repository: registry_name.repository.clone(),
+            requests: self.requests.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            auth_round_trips: self.auth_round_trips.load(Ordering::Relaxed),
+            blob_operations: self.blob_operations.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Hands out the recorders for every registry repository we talk to, so they can be reported on together.
+#[derive(Default)]
+pub struct Metrics {
+    recorders: Mutex<Vec<(RegistryName, Arc<MetricsRecorder>)>>,
+}
+
+impl Metrics {
+    /// Connections to the same registry repository share a recorder.
+    pub fn recorder_for(&self, registry_name: &RegistryName) -> Arc<MetricsRecorder> {
+        let mut recorders = self.recorders.lock().unwrap();
+        if let Some((_, recorder)) = recorders.iter().find(|(n, _)| n == registry_name) {
+            return Arc::clone(recorder);
+        }
+        let recorder = Arc::new(MetricsRecorder::default());
+        recorders.push((registry_name.clone(), Arc::clone(&recorder)));
+        recorder
+    }
+
+    pub fn report(&self) -> MetricsReport {
+        MetricsReport {
+            registries: self
+                .recorders
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(registry_name, recorder)| recorder.snapshot(registry_name))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MetricsFormat {
+    Json,
+    /// Text exposition format, for the node exporter textfile collector.
+    Prometheus,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct MetricsReport {
+    pub registries: Vec<RegistryMetrics>,
+}
+
+type RegistryValue = fn(&RegistryMetrics) -> u64;
+type BlobOperationValue = fn(&BlobOperationMetrics) -> f64;
+
+fn escape_label_value(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl MetricsReport {
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::default();
+        let registry_labels = |r: &RegistryMetrics| {
+            format!(
+                "registry=\"{}\",repository=\"{}\"",
+                escape_label_value(&r.registry),
+                escape_label_value(&r.repository)
+            )
+        };
+
+        let counters: [(&str, &str, RegistryValue); 3] = [
+            (
+                "minidock_registry_requests_total",
+                "Requests issued to the registry, including retries.",
+                |r| r.requests,
+            ),
+            (
+                "minidock_registry_retries_total",
+                "Requests retried after connection or server errors.",
+                |r| r.retries,
+            ),
+            (
+                "minidock_registry_auth_round_trips_total",
+                "Authentication flows run against the registry.",
+                |r| r.auth_round_trips,
+            ),
+        ];
+        for (name, help, value) in counters.iter() {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+            for r in self.registries.iter() {
+                let _ = writeln!(out, "{}{{{}}} {}", name, registry_labels(r), value(r));
+            }
+        }
+
+        // Totals per registry and operation.
+        let mut totals: BTreeMap<(String, BlobOperationKind), (u64, f64)> = BTreeMap::default();
+        for r in self.registries.iter() {
+            for op in r.blob_operations.iter() {
+                let total = totals
+                    .entry((registry_labels(r), op.operation))
+                    .or_default();
+                total.0 += op.bytes;
+                total.1 += op.seconds;
+            }
+        }
+        let _ = writeln!(out, "# HELP minidock_registry_blob_bytes_total Bytes moved by blob operations.\n# TYPE minidock_registry_blob_bytes_total counter");
+        for ((labels, operation), (bytes, _)) in totals.iter() {
+            let _ = writeln!(
+                out,
+                "minidock_registry_blob_bytes_total{{{},operation=\"{}\"}} {}",
+                labels,
+                operation.as_str(),
+                bytes
+            );
+        }
+        let _ = writeln!(out, "# HELP minidock_registry_blob_seconds_total Wall time spent in blob operations.\n# TYPE minidock_registry_blob_seconds_total counter");
+        for ((labels, operation), (_, seconds)) in totals.iter() {
+            let _ = writeln!(
+                out,
+                "minidock_registry_blob_seconds_total{{{},operation=\"{}\"}} {}",
+                labels,
+                operation.as_str(),
+                seconds
+            );
+        }
+
+        let blob_gauges: [(&str, &str, BlobOperationValue); 2] = [
+            (
+                "minidock_blob_operation_seconds",
+                "Wall time of a single blob operation.",
+                |op| op.seconds,
+            ),
+            (
+                "minidock_blob_operation_bytes_per_second",
+                "Throughput of a single blob operation.",
+                |op| op.bytes_per_second,
+            ),
+        ];
+        for (name, help, value) in blob_gauges.iter() {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+            for r in self.registries.iter() {
+                for op in r.blob_operations.iter() {
+                    let _ = writeln!(
+                        out,
+                        "{}{{{},operation=\"{}\",digest=\"{}\"}} {}",
+                        name,
+                        registry_labels(r),
+                        op.operation.as_str(),
+                        escape_label_value(&op.digest),
+                        value(op)
+                    );
+                }
+            }
+        }
+        out
+    }
+
+    /// Writes the report atomically, so a scraper never sees a partial file.
+    pub fn write_file(&self, path: &Path, format: MetricsFormat) -> Result<(), Error> {
+        let content = match format {
+            MetricsFormat::Json => serde_json::to_string_pretty(self)?,
+            MetricsFormat::Prometheus => self.to_prometheus(),
+        };
+        atomic_file::write(path, content)
+            .with_context(|| format!("Writing metrics to {:?}", path))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let metrics = Metrics::default();
+        let registry_name = RegistryName {
+            host: "gcr.io".to_string(),
+            repository: "team/app".to_string(),
+        };
+        let recorder = metrics.recorder_for(&registry_name);
+        recorder.record_request();
+        recorder.record_request();
+        recorder.record_retry();
+        recorder.record_auth_round_trip();
+        recorder.record_blob_operation(
+            "sha256:0",
+            BlobOperationKind::Upload,
+            2048,
+            Duration::from_secs(2),
+        );
+        // A second connection to the same repository shares the recorder.
+        metrics.recorder_for(&registry_name).record_request();
+
+        let report = metrics.report();
+        assert_eq!(report.registries.len(), 1);
+        assert_eq!(report.registries[0].requests, 3);
+        assert_eq!(
+            report.registries[0].blob_operations[0].bytes_per_second,
+            1024.0
+        );
+
+        let prometheus = report.to_prometheus();
+        assert!(prometheus.contains(
+            "minidock_registry_requests_total{registry=\"gcr.io\",repository=\"team/app\"} 3\n"
+        ));
+        assert!(prometheus.contains(
+            "minidock_registry_blob_bytes_total{registry=\"gcr.io\",repository=\"team/app\",operation=\"upload\"} 2048\n"
+        ));
+        assert!(prometheus.contains(
+            "minidock_blob_operation_seconds{registry=\"gcr.io\",repository=\"team/app\",operation=\"upload\",digest=\"sha256:0\"} 2\n"
+        ));
+    }
+}
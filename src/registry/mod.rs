@@ -1,12 +1,19 @@
+pub mod bandwidth;
+pub mod blob_index;
 mod http;
+pub mod metrics;
 pub mod ops;
 use std::{
+    collections::HashMap,
     path::{Path, PathBuf},
     sync::Arc,
 };
 
 use anyhow::{Context, Error};
+use bandwidth::BandwidthLimits;
+use metrics::Metrics;
 use indicatif::ProgressBar;
+use regex::Regex;
 
 #[derive(Debug, Clone)]
 pub struct DockerAuthenticationHelper {
@@ -48,6 +55,52 @@ pub struct ContentAndContentType {
     pub content: String,
 }
 
+/// Decides which tags may be moved when they already point at a different manifest.
+#[derive(Debug, Clone, Default)]
+pub struct TagPolicy {
+    pub no_clobber: bool,
+    pub immutable_tags: Option<Regex>,
+}
+
+impl TagPolicy {
+    pub fn new(no_clobber: bool, immutable_tag_pattern: Option<&str>) -> anyhow::Result<Self> {
+        let immutable_tags = immutable_tag_pattern
+            .map(|p| {
+                Regex::new(&format!("^(?:{})$", p))
+                    .with_context(|| format!("Invalid immutable tag pattern {:?}", p))
+            })
+            .transpose()?;
+        Ok(TagPolicy {
+            no_clobber,
+            immutable_tags,
+        })
+    }
+
+    pub fn allows_overwrite(&self, tag: &str) -> bool {
+        if self.no_clobber {
+            return false;
+        }
+        !self
+            .immutable_tags
+            .as_ref()
+            .map(|r| r.is_match(tag))
+            .unwrap_or(false)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Tag {tag} already points at {existing_digest}, and is not allowed to be overwritten")]
+pub struct TagAlreadyExists {
+    pub tag: String,
+    pub existing_digest: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("{uri} was not found in the registry")]
+pub struct NotFound {
+    pub uri: String,
+}
+
 #[async_trait::async_trait]
 pub trait RegistryCore {
     async fn fetch_manifest_as_string(&self, digest: &str) -> Result<ContentAndContentType, Error>;
@@ -58,20 +111,53 @@ pub trait RegistryCore {
         &self,
         manifest: &crate::container_specs::manifest::Manifest,
         tag: &str,
+        tag_policy: &TagPolicy,
     ) -> Result<Option<String>, Error>;
+
+    /// Uploads previously fetched manifest content as is, e.g. to restore what a tag pointed at.
+    async fn upload_manifest_content(
+        &self,
+        content: &ContentAndContentType,
+        reference: &str,
+    ) -> Result<String, Error>;
+
+    /// Resolves a tag or digest to the digest of the manifest, or image index, it points at.
+    /// The content is returned too, so the platforms of an index can be looked at.
+    async fn resolve_manifest(
+        &self,
+        reference: &str,
+    ) -> Result<(String, ContentAndContentType), Error>;
+
+    /// Like resolve_manifest, but a tag or digest the registry doesn't have is None rather than an error.
+    /// Any other failure is still an error, we can't tell whether the reference exists then.
+    async fn try_resolve_manifest(
+        &self,
+        reference: &str,
+    ) -> Result<Option<(String, ContentAndContentType)>, Error>;
 }
 
-#[derive(Debug)]
-pub struct RegistryName(String);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegistryName {
+    // Host of the registry, including the port when it isn't the default one for the scheme.
+    pub host: String,
+    pub repository: String,
+}
+
+impl RegistryName {
+    pub fn same_host(&self, other: &RegistryName) -> bool {
+        self.host.eq_ignore_ascii_case(&other.host)
+    }
+}
 
 impl std::fmt::Display for RegistryName {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+        write!(f, "{}/{}", self.host, self.repository)
     }
 }
 #[async_trait::async_trait]
 pub trait CopyOperations: Sync + Send {
     fn registry_name(&self) -> RegistryName;
+    /// Mounts the blob from another repository, this can only succeed when both live on the same registry host.
     async fn try_copy_from(
         &self,
         source_registry: &RegistryName,
@@ -83,6 +169,9 @@ pub trait CopyOperations: Sync + Send {
 pub trait BlobStore {
     async fn blob_exists(&self, digest: &str) -> Result<bool, Error>;
 
+    /// The size the registry reports for the blob, or None when it doesn't have it.
+    async fn blob_size(&self, digest: &str) -> Result<Option<u64>, Error>;
+
     async fn download_blob(
         &self,
         target_file: &Path,
@@ -98,6 +187,10 @@ pub trait BlobStore {
         length: u64,
         progress_bar: Option<ProgressBar>,
     ) -> Result<(), Error>;
+
+    /// Cancels the upload sessions we opened that never completed, e.g. because the uploads got aborted.
+    /// Returns how many sessions were cancelled.
+    async fn cancel_open_uploads(&self) -> Result<usize, Error>;
 }
 
 pub trait Registry: RegistryCore + BlobStore + CopyOperations {}
@@ -108,12 +201,96 @@ pub async fn from_maybe_domain_and_name<S: AsRef<str> + Send, S2: AsRef<str> + S
     registry_base: S,
     name: S2,
     docker_authorization_helpers: Arc<Vec<DockerAuthenticationHelper>>,
+    bandwidth_limits: Arc<BandwidthLimits>,
+    metrics: Arc<Metrics>,
 ) -> Result<Arc<dyn Registry>, Error> {
     let inner_reg = http::HttpRegistry::from_maybe_domain_and_name(
         registry_base,
         name,
         docker_authorization_helpers,
+        bandwidth_limits,
+        metrics,
+        &http::HostClient::new(),
     )
     .await?;
     Ok(Arc::new(inner_reg))
 }
+
+/// Connects to many repositories, sharing one connection pool and token cache per registry host between them.
+pub struct RegistryConnections {
+    docker_authorization_helpers: Arc<Vec<DockerAuthenticationHelper>>,
+    bandwidth_limits: Arc<BandwidthLimits>,
+    metrics: Arc<Metrics>,
+    host_clients: std::sync::Mutex<HashMap<String, Arc<http::HostClient>>>,
+}
+
+impl RegistryConnections {
+    pub fn new(
+        docker_authorization_helpers: Arc<Vec<DockerAuthenticationHelper>>,
+        bandwidth_limits: Arc<BandwidthLimits>,
+        metrics: Arc<Metrics>,
+    ) -> RegistryConnections {
+        RegistryConnections {
+            docker_authorization_helpers,
+            bandwidth_limits,
+            metrics,
+            host_clients: Default::default(),
+        }
+    }
+
+    pub async fn connect<S: AsRef<str> + Send, S2: AsRef<str> + Send>(
+        &self,
+        registry_base: S,
+        name: S2,
+    ) -> Result<Arc<dyn Registry>, Error> {
+        let host_client = Arc::clone(
+            self.host_clients
+                .lock()
+                .unwrap()
+                .entry(registry_base.as_ref().to_ascii_lowercase())
+                .or_default(),
+        );
+        let inner_reg = http::HttpRegistry::from_maybe_domain_and_name(
+            registry_base,
+            name,
+            Arc::clone(&self.docker_authorization_helpers),
+            Arc::clone(&self.bandwidth_limits),
+            Arc::clone(&self.metrics),
+            &host_client,
+        )
+        .await?;
+        Ok(Arc::new(inner_reg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_policy() {
+        let default_policy = TagPolicy::default();
+        assert!(default_policy.allows_overwrite("latest"));
+        assert!(default_policy.allows_overwrite("v1.2.3"));
+
+        let release_policy = TagPolicy::new(false, Some(r"v\d+\.\d+\.\d+")).unwrap();
+        assert!(release_policy.allows_overwrite("latest"));
+        assert!(!release_policy.allows_overwrite("v1.2.3"));
+        // The pattern has to match the whole tag
+        assert!(release_policy.allows_overwrite("v1.2.3-rc1"));
+
+        let no_clobber_policy = TagPolicy::new(true, None).unwrap();
+        assert!(!no_clobber_policy.allows_overwrite("latest"));
+    }
+
+    #[test]
+    fn test_registry_name_same_host() {
+        let name = |host: &str, repository: &str| RegistryName {
+            host: host.to_string(),
+            repository: repository.to_string(),
+        };
+        assert!(name("gcr.io", "team/app").same_host(&name("GCR.io", "mirror/team-app")));
+        assert!(!name("gcr.io", "team/app").same_host(&name("docker.io", "team/app")));
+        assert!(!name("localhost:5000", "a").same_host(&name("localhost:5001", "a")));
+    }
+}
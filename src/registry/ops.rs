@@ -5,7 +5,10 @@ use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use tokio::sync::Semaphore;
 
 use crate::container_specs::blob_reference::BlobReference;
+use crate::container_specs::Manifest;
+use crate::hash::sha256_value::Sha256Value;
 
+use super::blob_index::PresentBlobIndex;
 use super::Registry;
 use console::style;
 
@@ -31,6 +34,9 @@ pub struct ActionsTaken {
     copied_from_source_repository: usize,
     copied_from_source_repository_size: u64,
 
+    mounted_from_other_repository: usize,
+    mounted_from_other_repository_size: u64,
+
     uploaded_from_local: usize,
     uploaded_from_local_size: u64,
 
@@ -53,6 +59,11 @@ impl std::fmt::Display for ActionsTaken {
                 self.copied_from_source_repository,
                 size_to_string(self.copied_from_source_repository_size)
             ),
+            format!(
+                "Mounted from another repository:           {} entries, {}",
+                self.mounted_from_other_repository,
+                size_to_string(self.mounted_from_other_repository_size)
+            ),
             format!(
                 "Uploaded from local output state:          {} entries, {}",
                 self.uploaded_from_local,
@@ -80,6 +91,9 @@ impl ActionsTaken {
         self.copied_from_source_repository += other.copied_from_source_repository;
         self.copied_from_source_repository_size += other.copied_from_source_repository_size;
 
+        self.mounted_from_other_repository += other.mounted_from_other_repository;
+        self.mounted_from_other_repository_size += other.mounted_from_other_repository_size;
+
         self.uploaded_from_local += other.uploaded_from_local;
         self.uploaded_from_local_size += other.uploaded_from_local_size;
 
@@ -107,6 +121,14 @@ impl ActionsTaken {
         }
     }
 
+    pub fn mounted_from_other_repository(blob: &BlobReference) -> ActionsTaken {
+        ActionsTaken {
+            mounted_from_other_repository: 1,
+            mounted_from_other_repository_size: blob.size,
+            ..Default::default()
+        }
+    }
+
     pub fn uploaded_from_local(blob: &BlobReference) -> ActionsTaken {
         ActionsTaken {
             uploaded_from_local: 1,
@@ -135,12 +157,32 @@ pub struct RequestState {
     pub local_digests: HashMap<String, PathBuf>,
     pub destination_registry: Arc<dyn Registry>,
     pub source_registry: Option<Arc<dyn Registry>>,
+    /// Other repositories that blobs can be mounted from, tried in order before transferring any bytes.
+    pub mount_sources: Vec<Arc<dyn Registry>>,
     pub cache_path: PathBuf,
+    pub present_blob_index: Option<Arc<PresentBlobIndex>>,
 }
 
 impl RequestState {
     pub(super) async fn destination_present(&self, blob: &BlobReference) -> Result<bool, Error> {
-        self.destination_registry.blob_exists(&blob.digest).await
+        let registry_name = self.destination_registry.registry_name();
+        if let Some(present_blob_index) = &self.present_blob_index {
+            if present_blob_index.is_present(&registry_name, &blob.digest) {
+                return Ok(true);
+            }
+        }
+        let present = self.destination_registry.blob_exists(&blob.digest).await?;
+        if present {
+            self.record_destination_present(blob);
+        }
+        Ok(present)
+    }
+
+    fn record_destination_present(&self, blob: &BlobReference) {
+        if let Some(present_blob_index) = &self.present_blob_index {
+            present_blob_index
+                .record_present(&self.destination_registry.registry_name(), &blob.digest);
+        }
     }
 
     pub(super) async fn with_source_present(
@@ -157,6 +199,33 @@ impl RequestState {
             Ok(None)
         }
     }
+
+    // Asks the destination to mount the blob from the given repository, returns if the blob is present afterwards.
+    // Mounting across registry hosts can never work, so we don't issue any requests for those.
+    async fn try_mount_from(
+        &self,
+        blob: &BlobReference,
+        mount_source: &Arc<dyn Registry>,
+        concurrent_io_operations: &'static Semaphore,
+    ) -> Result<bool, Error> {
+        let source_registry_name = mount_source.registry_name();
+        if !source_registry_name.same_host(&self.destination_registry.registry_name()) {
+            return Ok(false);
+        }
+        let lock = concurrent_io_operations.acquire().await?;
+        if let Err(e) = self
+            .destination_registry
+            .try_copy_from(&source_registry_name, &blob.digest)
+            .await
+        {
+            tracing::debug!(
+                "Failed to copy a missing digest between remote repos, will continue: digest: {:#?}, from: {}, to: {}; error: {:#?}",
+                &blob.digest, &source_registry_name, self.destination_registry.registry_name(), e
+            );
+        }
+        drop(lock);
+        self.destination_present(blob).await
+    }
 }
 
 async fn finish_progress_bar_success(mp: Arc<MultiProgress>, pb: ProgressBar) {
@@ -198,35 +267,53 @@ pub async fn ensure_present(
 
     pb.set_message("Checking destination presence");
 
-    let destination_registry_name = request_state.destination_registry.registry_name();
     if request_state.destination_present(blob).await? {
         finish_progress_bar_success(mp, pb).await;
         return Ok(ActionsTaken::already_present(blob));
     }
 
-    pb.set_message("Checking source repository presence");
-    if let Some(source_registry) = request_state.with_source_present(blob).await? {
-        let source_registry_name = source_registry.registry_name();
-        pb.set_message("Try copy from source repository");
-        let lock = concurrent_io_operations.acquire().await?;
-        if let Err(e) = request_state
-            .destination_registry
-            .try_copy_from(&source_registry_name, &blob.digest)
+    for mount_source in request_state.mount_sources.iter() {
+        pb.set_message("Try mount from another repository");
+        // Mounting a blob the other repository doesn't have would open an upload session, so check first.
+        if !mount_source
+            .blob_exists(&blob.digest)
             .await
+            .unwrap_or(false)
         {
-            tracing::debug!(
-                "Failed to copy a missing digest between remote repos, will continue: digest: {:#?}, from: {}, to: {}; error: {:#?}",
-                &blob.digest, &source_registry_name, &destination_registry_name, e
-            );
+            continue;
         }
-        drop(lock);
-        pb.set_message("Checking destination presence post copy");
-        if request_state.destination_present(blob).await? {
+        if request_state
+            .try_mount_from(blob, mount_source, concurrent_io_operations)
+            .await?
+        {
             finish_progress_bar_success(mp, pb).await;
+            return Ok(ActionsTaken::mounted_from_other_repository(blob));
+        }
+    }
 
-            return Ok(ActionsTaken::copied_from_source_repository(blob));
+    let source_on_destination_host = request_state
+        .source_registry
+        .as_ref()
+        .map(|source_registry| {
+            source_registry
+                .registry_name()
+                .same_host(&request_state.destination_registry.registry_name())
+        })
+        .unwrap_or(false);
+    if source_on_destination_host {
+        pb.set_message("Checking source repository presence");
+        if let Some(source_registry) = request_state.with_source_present(blob).await? {
+            pb.set_message("Try copy from source repository");
+            if request_state
+                .try_mount_from(blob, source_registry, concurrent_io_operations)
+                .await?
+            {
+                finish_progress_bar_success(mp, pb).await;
+
+                return Ok(ActionsTaken::copied_from_source_repository(blob));
+            }
+            pb.set_message("Not found, copy failed.");
         }
-        pb.set_message("Not found, copy failed.");
     }
 
     if let Some(local_layer_path) = request_state.local_digests.get(&blob.digest) {
@@ -241,6 +328,7 @@ pub async fn ensure_present(
             .upload_blob(local_layer_path, &blob.digest, blob.size, Some(pb.clone()))
             .await?;
         drop(lock);
+        request_state.record_destination_present(blob);
         finish_progress_bar_success(mp, pb).await;
         return Ok(ActionsTaken::uploaded_from_local(blob));
     }
@@ -304,6 +392,7 @@ pub async fn ensure_present(
             .upload_blob(&expected_path, &blob.digest, blob.size, Some(pb.clone()))
             .await?;
         drop(lock);
+        request_state.record_destination_present(blob);
         finish_progress_bar_success(mp, pb).await;
 
         Ok(ActionsTaken::uploaded_data_from_source_repository(
@@ -314,3 +403,56 @@ pub async fn ensure_present(
         bail!("We still have remaining missing digests that we dont have locally. However we haven't been configured with a source repository, so we have no means to fetch them.")
     }
 }
+
+/// Re-fetches what was pushed to the registry, and returns a description of everything that doesn't match
+/// the manifest we pushed. An empty result means the registry has the full image under all the tags.
+pub async fn verify_pushed(
+    registry: &Arc<dyn Registry>,
+    manifest: &Manifest,
+    tags: &[String],
+) -> Result<Vec<String>, Error> {
+    let registry_name = registry.registry_name();
+    let manifest_digest = format!(
+        "sha256:{}",
+        Sha256Value::try_from(&manifest.to_bytes()?[..])?
+    );
+    let mut problems = Vec::default();
+
+    for tag in tags.iter() {
+        match registry.fetch_manifest_as_string(tag).await {
+            Ok(content) => {
+                let remote_digest = format!(
+                    "sha256:{}",
+                    Sha256Value::try_from(content.content.as_bytes())?
+                );
+                if remote_digest != manifest_digest {
+                    problems.push(format!(
+                        "{}:{} points at {}, expected {}",
+                        registry_name, tag, remote_digest, manifest_digest
+                    ));
+                }
+            }
+            Err(e) => problems.push(format!(
+                "{}:{} unable to fetch manifest: {:#}",
+                registry_name, tag, e
+            )),
+        }
+    }
+
+    for blob in std::iter::once(&manifest.config).chain(manifest.layers.iter()) {
+        match registry.blob_size(&blob.digest).await {
+            Ok(Some(size)) if size == blob.size => (),
+            Ok(Some(size)) => problems.push(format!(
+                "{}@{} has size {}, expected {}",
+                registry_name, blob.digest, size, blob.size
+            )),
+            Ok(None) => problems.push(format!("{}@{} is missing", registry_name, blob.digest)),
+            Err(e) => problems.push(format!(
+                "{}@{} unable to check blob: {:#}",
+                registry_name, blob.digest, e
+            )),
+        }
+    }
+
+    Ok(problems)
+}
@@ -0,0 +1,361 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{BufReader, Read},
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Error};
+
+use crate::container_specs::{blob_reference::BlobReferenceType, Manifest};
+
+// https://github.com/opencontainers/image-spec/blob/main/layer.md#whiteouts
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+// Same limit as Linux has on symlinks in a single lookup.
+const MAX_SYMLINKS_FOLLOWED: usize = 40;
+
+fn open_layer(
+    layer_path: &Path,
+    blob_reference_type: BlobReferenceType,
+) -> Result<tar::Archive<Box<dyn Read>>, Error> {
+    let f = BufReader::new(
+        File::open(layer_path).with_context(|| format!("Opening layer {:?}", layer_path))?,
+    );
+    let reader: Box<dyn Read> = match blob_reference_type {
+        BlobReferenceType::Layer => Box::new(f),
+        BlobReferenceType::LayerGz => Box::new(flate2::read::MultiGzDecoder::new(f)),
+        BlobReferenceType::LayerZstd => Box::new(zstd::stream::read::Decoder::new(f)?),
+        BlobReferenceType::Config => bail!("{:?} is a config, not a layer", layer_path),
+    };
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_overwrite(true);
+    Ok(archive)
+}
+
+/// Path of a layer entry relative to the root, refusing entries that climb out of it.
+fn entry_relative_path(path: &Path) -> Result<PathBuf, Error> {
+    let mut relative = PathBuf::default();
+    for component in path.components() {
+        match component {
+            Component::Normal(n) => relative.push(n),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => {
+                bail!(
+                    "Layer entry {:?} points outside of the root filesystem",
+                    path
+                )
+            }
+        }
+    }
+    Ok(relative)
+}
+
+/// Resolves path inside root the way it would be inside a chroot at root: symlinks are followed
+/// with absolute targets taken relative to root, and `..` never goes above it.
+fn resolve_in_root(root: &Path, path: &Path, follow_last: bool) -> Result<PathBuf, Error> {
+    fn push_components(pending: &mut VecDeque<OsString>, path: &Path) {
+        for component in path.components().rev() {
+            match component {
+                Component::Normal(n) => pending.push_front(n.to_os_string()),
+                Component::ParentDir => pending.push_front(OsString::from("..")),
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => (),
+            }
+        }
+    }
+
+    let mut resolved = PathBuf::default();
+    let mut pending = VecDeque::default();
+    push_components(&mut pending, path);
+    let mut symlinks_followed = 0;
+
+    while let Some(name) = pending.pop_front() {
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+        let candidate = resolved.join(&name);
+        let follow = follow_last || !pending.is_empty();
+        match std::fs::symlink_metadata(root.join(&candidate)) {
+            Ok(metadata) if follow && metadata.file_type().is_symlink() => {
+                symlinks_followed += 1;
+                if symlinks_followed > MAX_SYMLINKS_FOLLOWED {
+                    bail!("Too many levels of symbolic links resolving {:?}", path);
+                }
+                let target = std::fs::read_link(root.join(&candidate))?;
+                if target.is_absolute() {
+                    resolved = PathBuf::default();
+                }
+                push_components(&mut pending, &target);
+            }
+            _ => resolved = candidate,
+        }
+    }
+    Ok(root.join(resolved))
+}
+
+fn remove_existing(path: &Path) -> Result<(), Error> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path)?,
+        Ok(_) => std::fs::remove_file(path)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+// Whiteouts only hide what lower layers put there, so they are all applied before any content of the layer.
+fn apply_whiteouts(root: &Path, archive: &mut tar::Archive<Box<dyn Read>>) -> Result<(), Error> {
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry_relative_path(&entry.path()?)?;
+        let file_name = match path.file_name().and_then(OsStr::to_str) {
+            Some(file_name) if file_name.starts_with(WHITEOUT_PREFIX) => file_name,
+            _ => continue,
+        };
+        let dir = resolve_in_root(root, path.parent().unwrap_or(Path::new("")), true)?;
+        if file_name == OPAQUE_WHITEOUT {
+            if dir.is_dir() {
+                for child in std::fs::read_dir(&dir)? {
+                    remove_existing(&child?.path())?;
+                }
+            }
+        } else {
+            let hidden = &file_name[WHITEOUT_PREFIX.len()..];
+            if hidden.is_empty() || hidden == "." || hidden == ".." || hidden.contains('/') {
+                bail!("Whiteout {:?} doesn't name an entry to remove", path);
+            }
+            remove_existing(&dir.join(hidden))?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies a layer on top of the root filesystem at root, as a container runtime would. Whiteouts remove
+/// what lower layers added, and links are resolved within root, so no entry can write outside of it.
+/// Device nodes and fifos are skipped, creating them needs privileges we usually don't have.
+pub fn apply_layer(
+    root: &Path,
+    layer_path: &Path,
+    blob_reference_type: BlobReferenceType,
+) -> Result<(), Error> {
+    std::fs::create_dir_all(root)?;
+    apply_whiteouts(root, &mut open_layer(layer_path, blob_reference_type)?)
+        .with_context(|| format!("Applying whiteouts of layer {:?}", layer_path))?;
+
+    // Directory modes are set last, a read only directory would stop us from filling it.
+    let mut directory_modes = Vec::default();
+    let mut archive = open_layer(layer_path, blob_reference_type)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry_relative_path(&entry.path()?)?;
+        let file_name = match path.file_name() {
+            Some(file_name) => file_name.to_os_string(),
+            // The root itself.
+            None => continue,
+        };
+        if file_name.to_string_lossy().starts_with(WHITEOUT_PREFIX) {
+            continue;
+        }
+
+        let parent = resolve_in_root(root, path.parent().unwrap_or(Path::new("")), true)?;
+        std::fs::create_dir_all(&parent)?;
+        let target = parent.join(&file_name);
+
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_dir() {
+            if !std::fs::symlink_metadata(&target)
+                .map(|m| m.is_dir())
+                .unwrap_or(false)
+            {
+                remove_existing(&target)?;
+                std::fs::create_dir(&target)?;
+            }
+            directory_modes.push((target, entry.header().mode()?));
+            continue;
+        }
+
+        remove_existing(&target)?;
+        if entry_type.is_hard_link() {
+            let link_name = entry
+                .link_name()?
+                .with_context(|| format!("Hard link {:?} has no target", path))?;
+            let source = resolve_in_root(root, &link_name, false)?;
+            std::fs::hard_link(&source, &target).with_context(|| {
+                format!(
+                    "Linking {:?} to {:?} in the root filesystem",
+                    path, link_name
+                )
+            })?;
+        } else if entry_type.is_file() || entry_type.is_symlink() {
+            entry
+                .unpack(&target)
+                .with_context(|| format!("Unpacking {:?} into the root filesystem", path))?;
+        }
+    }
+
+    for (path, mode) in directory_modes.into_iter().rev() {
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Applies the layers in order into root, giving the filesystem a container of the image starts with.
+pub fn extract_layers(root: &Path, layers: &[(PathBuf, BlobReferenceType)]) -> Result<(), Error> {
+    for (layer_path, blob_reference_type) in layers.iter() {
+        apply_layer(root, layer_path, *blob_reference_type)?;
+    }
+    Ok(())
+}
+
+/// The layers of manifest in order, each read from the local file holding its digest.
+pub fn manifest_layers(
+    manifest: &Manifest,
+    local_paths: &HashMap<String, PathBuf>,
+) -> Result<Vec<(PathBuf, BlobReferenceType)>, Error> {
+    manifest
+        .layers
+        .iter()
+        .map(|layer| {
+            let local_path = local_paths
+                .get(&layer.digest)
+                .with_context(|| format!("Layer {} is not available locally", layer.digest))?;
+            Ok((local_path.clone(), layer.blob_reference_type))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append(
+        builder: &mut tar::Builder<Vec<u8>>,
+        path: &str,
+        entry_type: tar::EntryType,
+        link_name: Option<&str>,
+        content: &[u8],
+    ) -> Result<(), Error> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(if entry_type.is_dir() { 0o755 } else { 0o644 });
+        header.set_size(content.len() as u64);
+        // Written by hand, the builder refuses paths with `..` in them.
+        let name = &mut header.as_old_mut().name;
+        name[..path.len()].copy_from_slice(path.as_bytes());
+        if let Some(link_name) = link_name {
+            header.set_link_name(link_name)?;
+        }
+        header.set_cksum();
+        builder.append(&header, content)?;
+        Ok(())
+    }
+
+    fn write_layer(
+        dir: &Path,
+        name: &str,
+        entries: &[(&str, tar::EntryType, Option<&str>, &str)],
+    ) -> Result<PathBuf, Error> {
+        let mut builder = tar::Builder::new(Vec::default());
+        for (path, entry_type, link_name, content) in entries.iter() {
+            append(
+                &mut builder,
+                path,
+                *entry_type,
+                *link_name,
+                content.as_bytes(),
+            )?;
+        }
+        let mut encoder =
+            flate2::write::GzEncoder::new(Vec::default(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, &builder.into_inner()?)?;
+        let layer_path = dir.join(name);
+        std::fs::write(&layer_path, encoder.finish()?)?;
+        Ok(layer_path)
+    }
+
+    #[test]
+    fn test_extract_layers() -> Result<(), Error> {
+        use tar::EntryType::{Directory, Link, Regular, Symlink};
+
+        let layers = tempfile::tempdir()?;
+        let root_dir = tempfile::tempdir()?;
+        let root = root_dir.path().join("rootfs");
+
+        let base = write_layer(
+            layers.path(),
+            "base.tgz",
+            &[
+                ("./", Directory, None, ""),
+                ("etc/", Directory, None, ""),
+                ("etc/keep", Regular, None, "keep"),
+                ("etc/gone", Regular, None, "gone"),
+                ("opaque/old", Regular, None, "old"),
+                ("escape", Symlink, Some("/"), ""),
+                ("climb", Symlink, Some("../../.."), ""),
+            ],
+        )?;
+        let top = write_layer(
+            layers.path(),
+            "top.tgz",
+            &[
+                ("opaque/new", Regular, None, "new"),
+                ("etc/.wh.gone", Regular, None, ""),
+                ("opaque/.wh..wh..opq", Regular, None, ""),
+                ("escape/etc/through_link", Regular, None, "inside"),
+                ("climb/top", Regular, None, "inside"),
+                ("/etc/hard", Link, Some("../etc/keep"), ""),
+            ],
+        )?;
+        extract_layers(
+            &root,
+            &[
+                (base, BlobReferenceType::LayerGz),
+                (top, BlobReferenceType::LayerGz),
+            ],
+        )?;
+
+        assert_eq!(std::fs::read_to_string(root.join("etc/keep"))?, "keep");
+        assert!(!root.join("etc/gone").exists());
+        assert!(!root.join("opaque/old").exists());
+        // The opaque whiteout only hides lower layers, not what its own layer adds.
+        assert_eq!(std::fs::read_to_string(root.join("opaque/new"))?, "new");
+        // Links pointing out of the root resolve to inside of it.
+        assert_eq!(
+            std::fs::read_to_string(root.join("etc/through_link"))?,
+            "inside"
+        );
+        assert_eq!(std::fs::read_to_string(root.join("top"))?, "inside");
+        assert!(!root_dir.path().join("top").exists());
+        assert_eq!(std::fs::read_to_string(root.join("etc/hard"))?, "keep");
+
+        let escaping = write_layer(
+            layers.path(),
+            "escaping.tgz",
+            &[("../outside", Regular, None, "outside")],
+        )?;
+        assert!(apply_layer(&root, &escaping, BlobReferenceType::LayerGz).is_err());
+        assert!(!root_dir.path().join("outside").exists());
+
+        // Whiteouts naming the directory they are in, or its parent, would remove more than an entry.
+        std::fs::write(root_dir.path().join("sibling"), "sibling")?;
+        for (i, whiteout) in [".wh.", ".wh..", ".wh...", "etc/.wh..", "etc/.wh..."]
+            .iter()
+            .enumerate()
+        {
+            let bad_whiteout = write_layer(
+                layers.path(),
+                &format!("bad_whiteout_{}.tgz", i),
+                &[(whiteout, Regular, None, "")],
+            )?;
+            assert!(apply_layer(&root, &bad_whiteout, BlobReferenceType::LayerGz).is_err());
+        }
+        assert!(root_dir.path().join("sibling").exists());
+        assert_eq!(std::fs::read_to_string(root.join("etc/keep"))?, "keep");
+        Ok(())
+    }
+}
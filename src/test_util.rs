@@ -0,0 +1,51 @@
+use std::path::Path;
+
+use anyhow::Error;
+
+use crate::{
+    hash::sha256_value::{DataLen, Sha256Value},
+    LayerConfig, PathPair,
+};
+
+/// Directory the inputs of a test are written into, removed once dropped.
+pub(crate) struct TestDir(tempfile::TempDir);
+
+impl TestDir {
+    pub(crate) fn new() -> Result<TestDir, Error> {
+        Ok(TestDir(tempfile::tempdir()?))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        self.0.path()
+    }
+
+    /// How a merge config refers to name in the directory, whether it was written or not.
+    pub(crate) fn path_pair(&self, name: &str) -> PathPair {
+        PathPair {
+            short_path: name.to_string(),
+            path: self.path().join(name).to_string_lossy().to_string(),
+        }
+    }
+
+    pub(crate) fn write(&self, name: &str, content: impl AsRef<[u8]>) -> Result<PathPair, Error> {
+        std::fs::write(self.path().join(name), content)?;
+        Ok(self.path_pair(name))
+    }
+
+    /// Writes an uncompressed layer, giving it as the upload metadata lists local layers.
+    pub(crate) async fn write_layer(
+        &self,
+        name: &str,
+        content: impl AsRef<[u8]>,
+    ) -> Result<(LayerConfig, Sha256Value, DataLen), Error> {
+        let path_pair = self.write(name, content)?;
+        let (sha, len) = Sha256Value::from_path(Path::new(&path_pair.path)).await?;
+        let layer_config = LayerConfig {
+            layer_data: path_pair.path,
+            compressed_length: len.0 as u64,
+            outer_sha256: format!("sha256:{}", sha),
+            inner_sha256: format!("sha256:{}", sha),
+        };
+        Ok((layer_config, sha, len))
+    }
+}